@@ -1,6 +1,6 @@
 //! Buildscript which will save a `rust_g.dm` with the DLL's public API.
 
-use std::{fs::File, io::Write};
+use std::{fs::File, io::Write, process::Command};
 
 macro_rules! feature_dm_file {
     ($name:expr) => {
@@ -15,6 +15,17 @@ macro_rules! feature_dm_exists {
 }
 
 fn main() {
+    let exports = exports();
+    let calls = dm_calls();
+    check_wrappers(&exports, &calls);
+    // Anything called or named by hand already is left alone.
+    let mut wrapped = dm_definitions();
+    wrapped.extend(
+        calls
+            .into_iter()
+            .map(|(_, symbol, _)| symbol.trim_start_matches("byond:").to_owned()),
+    );
+
     let mut f = File::create("target/rust_g.dm").unwrap();
 
     // header
@@ -25,18 +36,397 @@ fn main() {
     )
     .unwrap();
 
+    let mut features = Vec::new();
     for (key, _value) in std::env::vars() {
         // CARGO_FEATURE_<name> — For each activated feature of the package being built, this environment variable will be present where <name> is the name of the feature uppercased and having - translated to _.
         if let Some(uprfeature) = key.strip_prefix("CARGO_FEATURE_") {
-            let feature = uprfeature.to_lowercase().replace('_', "-"); // actual proper name of the enabled feature
-            if feature_dm_exists!(&feature) {
-                writeln!(
-                    f,
-                    "{}",
-                    std::fs::read_to_string(feature_dm_file!(&feature)).unwrap()
-                )
-                .unwrap();
+            features.push(uprfeature.to_lowercase());
+        }
+    }
+    // Keep the output stable between builds.
+    features.sort();
+
+    write_generated(&mut f, &exports, &wrapped, &features, "main");
+    for feature in &features {
+        let dm_name = feature.replace('_', "-"); // actual proper name of the enabled feature
+        if feature_dm_exists!(&dm_name) {
+            writeln!(
+                f,
+                "{}",
+                std::fs::read_to_string(feature_dm_file!(&dm_name)).unwrap()
+            )
+            .unwrap();
+        }
+        write_generated(&mut f, &exports, &wrapped, &features, feature);
+    }
+
+    write_build_info(features);
+}
+
+/// An export declared with `byond_fn!`, as far as `build.rs` can tell from the source.
+struct Export {
+    name: String,
+    args: Vec<String>,
+    /// Whether it takes `...rest`, i.e. any number of extra arguments.
+    variadic: bool,
+    /// Declared with `byond_fn_native!`, to be called as `"byond:name"`.
+    native: bool,
+    /// The `//` comment directly above the declaration.
+    docs: Vec<String>,
+    /// The feature gating the module, or `main` for the always-built ones.
+    feature: String,
+    /// Features the declaration itself is gated on, e.g. `buffer_hash` on `hash`.
+    extra_features: Vec<String>,
+    file: String,
+}
+
+/// Maps each module in `src/lib.rs` to the feature it's gated on.
+fn module_features() -> Vec<(String, String)> {
+    let lib = std::fs::read_to_string("src/lib.rs").unwrap();
+    let mut modules = Vec::new();
+    let mut feature = None;
+    for line in lib.lines().map(str::trim) {
+        if let Some(name) = cfg_feature(line) {
+            feature = Some(name);
+            continue;
+        }
+        let module = line
+            .strip_prefix("pub ")
+            .unwrap_or(line)
+            .strip_prefix("mod ")
+            .and_then(|rest| rest.strip_suffix(';'));
+        if let Some(module) = module {
+            let feature = feature.take().unwrap_or_else(|| "main".to_owned());
+            modules.push((module.to_owned(), feature));
+        }
+        feature = None;
+    }
+    modules
+}
+
+/// The feature named by a `#[cfg(feature = "...")]` line.
+fn cfg_feature(line: &str) -> Option<String> {
+    line.strip_prefix("#[cfg(feature = \"")
+        .and_then(|rest| rest.strip_suffix("\")]"))
+        .map(str::to_owned)
+}
+
+/// Finds every `byond_fn!` declaration in the modules listed in `src/lib.rs`.
+fn exports() -> Vec<Export> {
+    let mut exports = Vec::new();
+    for (module, feature) in module_features() {
+        let file = format!("src/{module}.rs");
+        let source = std::fs::read_to_string(&file).unwrap();
+        let lines: Vec<&str> = source.lines().collect();
+        for (i, line) in lines.iter().enumerate() {
+            let (rest, native) = match line.strip_prefix("byond_fn!(") {
+                Some(rest) => (rest, false),
+                None => match line.strip_prefix("byond_fn_native!(") {
+                    Some(rest) => (rest, true),
+                    None => continue,
+                },
+            };
+            // Either `byond_fn!(fn name(..) {` or the signature on the next line.
+            let signature = if rest.trim().is_empty() {
+                lines.get(i + 1).map_or("", |next| next.trim())
+            } else {
+                rest.trim()
+            };
+            let Some((name, args)) = signature
+                .strip_prefix("fn ")
+                .and_then(|signature| signature.split_once('('))
+                .and_then(|(name, rest)| Some((name, rest.split_once(')')?.0)))
+            else {
+                panic!("{file}:{}: can't parse byond_fn! signature", i + 1);
+            };
+
+            let mut args: Vec<String> = args
+                .split(',')
+                .map(|arg| arg.trim().to_owned())
+                .filter(|arg| !arg.is_empty())
+                .collect();
+            let variadic = args.last().is_some_and(|arg| arg.starts_with("..."));
+            if variadic {
+                args.pop();
             }
+
+            let mut docs = Vec::new();
+            let mut extra_features = Vec::new();
+            for above in lines[..i].iter().rev().map(|line| line.trim()) {
+                if let Some(feature) = cfg_feature(above) {
+                    extra_features.push(feature);
+                } else if let Some(comment) = above.strip_prefix("//") {
+                    docs.insert(0, comment.trim().to_owned());
+                } else {
+                    break;
+                }
+            }
+
+            exports.push(Export {
+                name: name.trim().to_owned(),
+                args,
+                variadic,
+                native,
+                docs,
+                feature: feature.clone(),
+                extra_features,
+                file: file.clone(),
+            });
         }
     }
+    exports
+}
+
+/// The hand-written `dmsrc/*.dm` files, as `(path, contents)`.
+fn dm_sources() -> Vec<(String, String)> {
+    let mut paths: Vec<_> = std::fs::read_dir("dmsrc")
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "dm"))
+        .collect();
+    paths.sort();
+    paths
+        .into_iter()
+        .map(|path| {
+            let source = std::fs::read_to_string(&path).unwrap();
+            (path.display().to_string(), source)
+        })
+        .collect()
+}
+
+/// The `rustg_*` procs and macros defined in the hand-written DM.
+fn dm_definitions() -> Vec<String> {
+    let mut names = Vec::new();
+    for (_, source) in dm_sources() {
+        for line in source.lines().map(str::trim) {
+            let name = line
+                .strip_prefix("#define ")
+                .or_else(|| line.strip_prefix("/proc/"))
+                .and_then(|rest| rest.split_once('('))
+                .map(|(name, _)| name);
+            if let Some(name) = name.filter(|name| name.starts_with("rustg_")) {
+                names.push(name.to_owned());
+            }
+        }
+    }
+    names
+}
+
+/// Calls to rust_g in the hand-written DM, as `(file, symbol, argument count)`.
+fn dm_calls() -> Vec<(String, String, usize)> {
+    let mut calls = Vec::new();
+    for (path, source) in dm_sources() {
+        let mut rest = source.as_str();
+        while let Some(start) = rest.find("RUST_G, \"") {
+            rest = &rest[start + "RUST_G, \"".len()..];
+            let Some((symbol, after)) = rest.split_once('"') else {
+                break;
+            };
+            let argc = after.strip_prefix(")(").map_or(0, count_dm_args);
+            calls.push((path.clone(), symbol.to_owned(), argc));
+            rest = after;
+        }
+    }
+    calls
+}
+
+/// Counts the arguments of a DM call, given the text after its opening parenthesis.
+fn count_dm_args(text: &str) -> usize {
+    let (mut depth, mut in_string, mut count, mut empty) = (0, false, 1, true);
+    for c in text.chars() {
+        match c {
+            '"' => in_string = !in_string,
+            _ if in_string => {}
+            '(' | '[' => depth += 1,
+            ')' if depth == 0 => break,
+            ')' | ']' => depth -= 1,
+            ',' if depth == 0 => count += 1,
+            _ => {}
+        }
+        if !c.is_whitespace() {
+            empty = false;
+        }
+    }
+    if empty {
+        0
+    } else {
+        count
+    }
+}
+
+/// Fails the build if the hand-written DM calls something we don't export, or
+/// passes a different number of arguments than the export declares.
+fn check_wrappers(exports: &[Export], calls: &[(String, String, usize)]) {
+    for (path, symbol, argc) in calls {
+        let (name, native) = match symbol.strip_prefix("byond:") {
+            Some(name) => (name, true),
+            None => (symbol.as_str(), false),
+        };
+        let Some(export) = exports.iter().find(|export| export.name == name) else {
+            panic!("{path} calls \"{symbol}\", which isn't exported by any byond_fn!");
+        };
+        if export.native != native {
+            panic!(
+                "{path} calls \"{symbol}\", but {} declares it with the other calling convention",
+                export.file
+            );
+        }
+        // Missing arguments would arrive as "", but an argument added to an
+        // export should be passed on by every wrapper, even if only as "".
+        if export.variadic && *argc < export.args.len()
+            || !export.variadic && *argc != export.args.len()
+        {
+            panic!(
+                "{path} passes {argc} arguments to \"{symbol}\", which takes {} ({})",
+                export.args.len(),
+                export.file,
+            );
+        }
+    }
+}
+
+/// Writes wrappers for the exports of `feature` which aren't wrapped by hand.
+fn write_generated(
+    f: &mut File,
+    exports: &[Export],
+    wrapped: &[String],
+    features: &[String],
+    feature: &str,
+) {
+    for export in exports {
+        // Older exports carry an `rg_` prefix instead.
+        let name = export.name.strip_prefix("rg_").unwrap_or(&export.name);
+        if export.feature != feature
+            || wrapped.contains(&export.name)
+            || wrapped.contains(&format!("rustg_{name}"))
+            || !export
+                .extra_features
+                .iter()
+                .all(|extra| features.contains(extra))
+        {
+            continue;
+        }
+        writeln!(f, "// Generated from {}", export.file).unwrap();
+        for line in &export.docs {
+            writeln!(f, "/// {line}").unwrap();
+        }
+        let args = export.args.join(", ");
+        if export.native {
+            // byondapi only exists from 515 on.
+            writeln!(
+                f,
+                "#if DM_VERSION >= 515\n#define rustg_{name}({args}) call_ext(RUST_G, \"byond:{}\")({args})\n#endif\n",
+                export.name
+            )
+            .unwrap();
+        } else if export.variadic {
+            let params: Vec<&str> = export
+                .args
+                .iter()
+                .map(String::as_str)
+                .chain(["..."])
+                .collect();
+            writeln!(
+                f,
+                "/proc/rustg_{name}({})\n\treturn RUSTG_CALL(RUST_G, \"{}\")(arglist(args))\n",
+                params.join(", "),
+                export.name
+            )
+            .unwrap();
+        } else if export.args.is_empty() {
+            writeln!(
+                f,
+                "/proc/rustg_{name}() return RUSTG_CALL(RUST_G, \"{}\")()\n",
+                export.name
+            )
+            .unwrap();
+        } else {
+            writeln!(
+                f,
+                "#define rustg_{name}({args}) RUSTG_CALL(RUST_G, \"{}\")({args})\n",
+                export.name
+            )
+            .unwrap();
+        }
+    }
+}
+
+/// Hands `get_features` a JSON description of this build, via `RUSTG_BUILD_INFO`.
+fn write_build_info(features: Vec<String>) {
+    // Only report the features which are modules, leaving out optional
+    // dependencies, meta-features like `default` and internal ones like `jobs`.
+    let modules = module_feature_names();
+    let tls = if features.iter().any(|f| f == "native_tls") {
+        "native_tls"
+    } else if features.iter().any(|f| f == "rustls_tls") {
+        "rustls_tls"
+    } else {
+        ""
+    };
+    let mut features: Vec<String> = features
+        .into_iter()
+        .filter(|f| modules.contains(f))
+        .collect();
+    features.sort();
+    // Cargo passes these to build scripts only.
+    let target = std::env::var("TARGET").unwrap_or_default();
+    let profile = std::env::var("PROFILE").unwrap_or_default();
+    // Naming any file here stops Cargo rerunning this whenever anything in the
+    // package changes, so everything read above is listed too.
+    for path in ["build.rs", "Cargo.toml", "src", "dmsrc"] {
+        println!("cargo:rerun-if-changed={path}");
+    }
+    // Rebuild when HEAD moves, whether by checking out or committing. A branch
+    // which was never committed to since packing only exists in packed-refs.
+    let head = std::fs::read_to_string(".git/HEAD").ok();
+    let branch = head
+        .as_deref()
+        .and_then(|head| head.strip_prefix("ref: "))
+        .map(|branch| format!(".git/{}", branch.trim()));
+    for path in [
+        Some(".git/HEAD".to_owned()),
+        branch,
+        Some(".git/packed-refs".to_owned()),
+    ]
+    .into_iter()
+    .flatten()
+    {
+        if std::path::Path::new(&path).exists() {
+            println!("cargo:rerun-if-changed={path}");
+        }
+    }
+    let revision = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+        .unwrap_or_default();
+
+    let quote = |value: &str| format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""));
+    let features: Vec<String> = features.iter().map(|f| quote(f)).collect();
+    println!(
+        "cargo:rustc-env=RUSTG_BUILD_INFO={{\"version\":{},\"features\":[{}],\"git_revision\":{},\"target\":{},\"profile\":{},\"tls\":{}}}",
+        quote(env!("CARGO_PKG_VERSION")),
+        features.join(","),
+        quote(&revision),
+        quote(&target),
+        quote(&profile),
+        quote(tls),
+    );
+}
+
+/// The user-facing features, as listed in `all` in `Cargo.toml`, less the TLS
+/// backends, which are reported separately.
+fn module_feature_names() -> Vec<String> {
+    let manifest = std::fs::read_to_string("Cargo.toml").unwrap();
+    manifest
+        .lines()
+        .skip_while(|line| !line.starts_with("all = ["))
+        .skip(1)
+        .take_while(|line| !line.starts_with(']'))
+        .map(|line| line.trim().trim_end_matches(',').trim_matches('"'))
+        .filter(|name| !name.is_empty() && !name.ends_with("_tls"))
+        .map(str::to_owned)
+        .collect()
 }
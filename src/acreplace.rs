@@ -1,3 +1,7 @@
+use crate::{
+    byond::deserialize_byond_bool,
+    error::{Error, Reply, Result},
+};
 use aho_corasick::{AhoCorasick, AhoCorasickBuilder, MatchKind, StartKind};
 use serde::Deserialize;
 use std::{cell::RefCell, collections::hash_map::HashMap};
@@ -32,17 +36,7 @@ impl AhoCorasickOptions {
     }
 }
 
-fn deserialize_byond_bool<'de, D>(deserializer: D) -> Result<bool, D::Error>
-where
-    D: serde::de::Deserializer<'de>,
-{
-    match u8::deserialize(deserializer)? {
-        0 => Ok(false),
-        _ => Ok(true),
-    }
-}
-
-fn deserialize_matchkind<'de, D>(deserializer: D) -> Result<MatchKind, D::Error>
+fn deserialize_matchkind<'de, D>(deserializer: D) -> std::result::Result<MatchKind, D::Error>
 where
     D: serde::de::Deserializer<'de>,
 {
@@ -58,41 +52,50 @@ thread_local! {
 }
 
 byond_fn!(fn setup_acreplace(key, patterns_json, replacements_json) {
-    let patterns: Vec<String> = serde_json::from_str(patterns_json).ok()?;
-    let replacements: Vec<String> = serde_json::from_str(replacements_json).ok()?;
-    let ac = AhoCorasickBuilder::new().build(patterns).unwrap(); // Recommends to just unwrap in the docs
-    CREPLACE_MAP.with(|cell| {
-        let mut map = cell.borrow_mut();
-        map.insert(key.to_owned(), Replacements { automaton: ac, replacements });
-    });
-    Some("")
+    Reply::value_or_none((|| {
+        let patterns: Vec<String> = serde_json::from_str(patterns_json)?;
+        let replacements: Vec<String> = serde_json::from_str(replacements_json)?;
+        let ac = AhoCorasickBuilder::new().build(patterns).unwrap(); // Recommends to just unwrap in the docs
+        CREPLACE_MAP.with(|cell| {
+            let mut map = cell.borrow_mut();
+            map.insert(key.to_owned(), Replacements { automaton: ac, replacements });
+        });
+        Ok("")
+    })())
 });
 
 byond_fn!(fn setup_acreplace_with_options(key, options_json, patterns_json, replacements_json) {
-    let options: AhoCorasickOptions = serde_json::from_str(options_json).ok()?;
-    let patterns: Vec<String> = serde_json::from_str(patterns_json).ok()?;
-    let replacements: Vec<String> = serde_json::from_str(replacements_json).ok()?;
-    let ac = options.auto_configure_and_build(&patterns);
-    CREPLACE_MAP.with(|cell| {
-        let mut map = cell.borrow_mut();
-        map.insert(key.to_owned(), Replacements { automaton: ac, replacements });
-    });
-    Some("")
+    Reply::value_or_none((|| {
+        let options: AhoCorasickOptions = serde_json::from_str(options_json)?;
+        let patterns: Vec<String> = serde_json::from_str(patterns_json)?;
+        let replacements: Vec<String> = serde_json::from_str(replacements_json)?;
+        let ac = options.auto_configure_and_build(&patterns);
+        CREPLACE_MAP.with(|cell| {
+            let mut map = cell.borrow_mut();
+            map.insert(key.to_owned(), Replacements { automaton: ac, replacements });
+        });
+        Ok("")
+    })())
 });
 
 byond_fn!(fn acreplace(key, text) {
-    CREPLACE_MAP.with(|cell| -> Option<String> {
-        let map = cell.borrow_mut();
-        let replacements = map.get(key)?;
-        Some(replacements.automaton.replace_all(text, &replacements.replacements))
-    })
+    Reply::value_or_none(replace(key, text, None))
 });
 
 byond_fn!(fn acreplace_with_replacements(key, text, replacements_json) {
-    let call_replacements: Vec<String> = serde_json::from_str(replacements_json).ok()?;
-    CREPLACE_MAP.with(|cell| -> Option<String> {
-        let map = cell.borrow_mut();
-        let replacements = map.get(key)?;
-        Some(replacements.automaton.replace_all(text, &call_replacements))
-    })
+    Reply::value_or_none((|| {
+        let call_replacements: Vec<String> = serde_json::from_str(replacements_json)?;
+        replace(key, text, Some(&call_replacements))
+    })())
 });
+
+/// Replaces with `replacements` if given, and the ones set up with the key
+/// otherwise.
+fn replace(key: &str, text: &str, replacements: Option<&[String]>) -> Result<String> {
+    CREPLACE_MAP.with(|cell| {
+        let map = cell.borrow();
+        let set_up = map.get(key).ok_or(Error::InvalidReplacements)?;
+        let replacements = replacements.unwrap_or(&set_up.replacements);
+        Ok(set_up.automaton.replace_all(text, replacements))
+    })
+}
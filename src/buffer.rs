@@ -0,0 +1,110 @@
+//! Binary buffers, referred to from BYOND by handle.
+//!
+//! BYOND strings can't hold NUL bytes and everything passed in is read as
+//! UTF-8, so binary data stays on this side and only ever crosses the FFI
+//! boundary encoded.
+use crate::{
+    error::{Error, Reply, Result},
+    sandbox,
+};
+use base64::Engine;
+use std::{
+    collections::BTreeMap,
+    fs,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+// Shared with the job workers, which look buffers up when they run queries.
+static BUFFERS: Mutex<BTreeMap<usize, Arc<Vec<u8>>>> = Mutex::new(BTreeMap::new());
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+byond_fn!(fn buffer_from_base64(data) {
+    Reply::value_or_none(
+        base64::prelude::BASE64_STANDARD
+            .decode(data)
+            .map_err(Error::from)
+            .and_then(insert)
+            .map(|id| id.to_string()),
+    )
+});
+
+byond_fn!(fn buffer_from_hex(data) {
+    let bytes = hex::decode(data).map_err(|_| Error::HexDecode);
+    Reply::value_or_none(bytes.and_then(insert).map(|id| id.to_string()))
+});
+
+byond_fn!(fn buffer_from_file(path) {
+    let bytes = sandbox::check(path).and_then(|path| Ok(fs::read(path)?));
+    Reply::value_or_none(bytes.and_then(insert).map(|id| id.to_string()))
+});
+
+byond_fn!(fn buffer_to_base64(handle) {
+    Reply::value_or_none(get(handle).map(|bytes| base64::prelude::BASE64_STANDARD.encode(&*bytes)))
+});
+
+byond_fn!(fn buffer_to_hex(handle) {
+    Reply::value_or_none(get(handle).map(|bytes| hex::encode(&*bytes)))
+});
+
+byond_fn!(fn buffer_len(handle) {
+    Reply::value_or_none(get(handle).map(|bytes| bytes.len().to_string()))
+});
+
+byond_fn!(fn buffer_write_file(handle, path) {
+    Reply::error_or_none(get(handle).and_then(|bytes| write_file(&bytes, path)))
+});
+
+#[cfg(feature = "hash")]
+byond_fn!(fn buffer_hash(handle, algorithm) {
+    Reply::value_or_none(get(handle).and_then(|bytes| crate::hash::hash_algorithm(algorithm, &*bytes)))
+});
+
+byond_fn!(fn buffer_free(handle) {
+    Reply::error_or_none(remove(handle))
+});
+
+fn insert(bytes: Vec<u8>) -> Result<usize> {
+    let mut buffers = BUFFERS.lock().map_err(|_| Error::Poisoned)?;
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    buffers.insert(id, Arc::new(bytes));
+    Ok(id)
+}
+
+/// Looks up the contents of a buffer for other modules, e.g. to send it as an
+/// HTTP body or bind it as an SQL parameter.
+pub fn get(handle: &str) -> Result<Arc<Vec<u8>>> {
+    let id = handle.parse::<usize>()?;
+    let buffers = BUFFERS.lock().map_err(|_| Error::Poisoned)?;
+    buffers.get(&id).cloned().ok_or(Error::InvalidBuffer)
+}
+
+fn remove(handle: &str) -> Result<()> {
+    let id = handle.parse::<usize>()?;
+    let mut buffers = BUFFERS.lock().map_err(|_| Error::Poisoned)?;
+    buffers.remove(&id).map(|_| ()).ok_or(Error::InvalidBuffer)
+}
+
+fn write_file(bytes: &[u8], path: &str) -> Result<()> {
+    let path = sandbox::check(path)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    Ok(fs::write(path, bytes)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffers_round_trip() {
+        let handle = insert(vec![0, 159, 146, 150, 0]).unwrap().to_string();
+        assert_eq!(get(&handle).unwrap().as_slice(), &[0, 159, 146, 150, 0]);
+        remove(&handle).unwrap();
+        assert!(matches!(get(&handle), Err(Error::InvalidBuffer)));
+        assert!(matches!(remove("nonsense"), Err(Error::ParseInt(_))));
+    }
+}
@@ -1,16 +1,35 @@
+use crate::{
+    error::{envelope_enabled, envelope_err, envelope_ok, Error, Reply, Result},
+    sandbox,
+};
 use std::{
+    any::Any,
+    backtrace::Backtrace,
     borrow::Cow,
     cell::RefCell,
     ffi::{CStr, CString},
+    fmt::Write as _,
+    fs::{self, OpenOptions},
+    io::Write as _,
     os::raw::{c_char, c_int},
+    panic::{self, AssertUnwindSafe, Location},
+    path::PathBuf,
     slice,
+    sync::{Mutex, Once},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 static EMPTY_STRING: c_char = 0;
 thread_local! {
     static RETURN_STRING: RefCell<CString> = RefCell::new(CString::default());
+    static THREAD_PANIC: RefCell<Option<PanicReport>> = const { RefCell::new(None) };
 }
 
+static PANIC_HOOK: Once = Once::new();
+static LAST_PANIC: Mutex<Option<String>> = Mutex::new(None);
+static PANIC_LOG: Mutex<Option<PathBuf>> = Mutex::new(None);
+
 pub unsafe fn parse_args<'a>(argc: c_int, argv: *const *const c_char) -> Vec<Cow<'a, str>> {
     unsafe {
         slice::from_raw_parts(argv, argc as usize)
@@ -51,7 +70,7 @@ macro_rules! byond_fn {
             _argc: ::std::os::raw::c_int, _argv: *const *const ::std::os::raw::c_char
         ) -> *const ::std::os::raw::c_char {
             let closure = || ($body);
-            $crate::byond::byond_return(closure().map(From::from))
+            $crate::byond::byond_return($crate::byond::catch_panic(closure))
         }
     };
 
@@ -73,14 +92,345 @@ macro_rules! byond_fn {
             )?
 
             let closure = || ($body);
-            $crate::byond::byond_return(closure().map(From::from))
+            $crate::byond::byond_return($crate::byond::catch_panic(closure))
+        }
+    };
+}
+
+/// Declares an export for `call_ext(RUST_G, "byond:name")`, which takes and
+/// returns numbers without going through strings. Arguments which aren't
+/// numbers come through as `None`, as does a missing return value.
+#[macro_export]
+macro_rules! byond_fn_native {
+    (fn $name:ident($($arg:ident),*) $body:block) => {
+        #[no_mangle]
+        #[allow(clippy::missing_safety_doc)]
+        pub unsafe extern "C" fn $name(
+            _argc: u32, _argv: *const $crate::byond::ByondValue
+        ) -> $crate::byond::ByondValue {
+            #[allow(unused_mut, unused_variables)]
+            let mut __args = unsafe { $crate::byond::parse_native_args(_argc, _argv) }.iter();
+            $(
+                let $arg: Option<f32> = __args.next().and_then($crate::byond::ByondValue::as_number);
+            )*
+
+            let closure = || ($body);
+            $crate::byond::catch_panic_native(closure)
+        }
+    };
+}
+
+// ----------------------------------------------------------------------------
+// Native values
+
+const BYOND_NULL: u8 = 0x00;
+const BYOND_NUMBER: u8 = 0x2A;
+
+/// A value passed by `call_ext(RUST_G, "byond:name")` in BYOND 515 and later,
+/// laid out like byondapi's `CByondValue`. Only numbers are understood here;
+/// anything else reads as null, since strings and references need byondapi's
+/// own functions to get at.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ByondValue {
+    kind: u8,
+    _junk: [u8; 3],
+    data: u32,
+}
+
+impl ByondValue {
+    pub const NULL: Self = Self {
+        kind: BYOND_NULL,
+        _junk: [0; 3],
+        data: 0,
+    };
+
+    pub fn number(value: f32) -> Self {
+        Self {
+            kind: BYOND_NUMBER,
+            _junk: [0; 3],
+            data: value.to_bits(),
+        }
+    }
+
+    pub fn as_number(&self) -> Option<f32> {
+        (self.kind == BYOND_NUMBER).then(|| f32::from_bits(self.data))
+    }
+}
+
+pub unsafe fn parse_native_args<'a>(argc: u32, argv: *const ByondValue) -> &'a [ByondValue] {
+    if argv.is_null() {
+        return &[];
+    }
+    unsafe { slice::from_raw_parts(argv, argc as usize) }
+}
+
+/// Like `catch_panic`, for exports returning a number. A panic comes back as
+/// null and is left for `panic_last` to report.
+pub fn catch_panic_native<F: FnOnce() -> Option<f32>>(f: F) -> ByondValue {
+    install_panic_hook();
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Some(value)) => ByondValue::number(value),
+        Ok(None) => ByondValue::NULL,
+        Err(_) => {
+            THREAD_PANIC.with(|cell| cell.take());
+            ByondValue::NULL
+        }
+    }
+}
+
+// ----------------------------------------------------------------------------
+// Panic capture
+
+/// What is known about a panic, recorded by the panic hook on the panicking thread.
+struct PanicReport {
+    thread: String,
+    message: String,
+    location: String,
+    backtrace: String,
+}
+
+impl PanicReport {
+    fn new(payload: &(dyn Any + Send), location: Option<&Location>) -> Self {
+        let message = if let Some(message) = payload.downcast_ref::<&str>() {
+            (*message).to_owned()
+        } else if let Some(message) = payload.downcast_ref::<String>() {
+            message.clone()
+        } else {
+            "Box<dyn Any>".to_owned()
+        };
+        Self {
+            thread: thread::current().name().unwrap_or("<unnamed>").to_owned(),
+            message,
+            location: location.map_or_else(String::new, ToString::to_string),
+            backtrace: Backtrace::force_capture().to_string(),
+        }
+    }
+
+    /// The short form handed back to BYOND by a panicking export.
+    fn to_json(&self) -> String {
+        format!(
+            "{{\"panic\":{},\"location\":{}}}",
+            json_string(&self.message),
+            json_string(&self.location)
+        )
+    }
+
+    fn to_json_full(&self) -> String {
+        format!(
+            "{{\"panic\":{},\"location\":{},\"thread\":{},\"backtrace\":{}}}",
+            json_string(&self.message),
+            json_string(&self.location),
+            json_string(&self.thread),
+            json_string(&self.backtrace)
+        )
+    }
+}
+
+/// Installs a panic hook which records every panic, on any thread, so it can be
+/// reported to BYOND and optionally written to the crash log. The previous hook
+/// still runs afterwards.
+pub fn install_panic_hook() {
+    PANIC_HOOK.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            let report = PanicReport::new(info.payload(), info.location());
+            if let Ok(mut last) = LAST_PANIC.lock() {
+                *last = Some(report.to_json_full());
+            }
+            write_panic_log(&report);
+            THREAD_PANIC.with(|cell| cell.replace(Some(report)));
+            previous(info);
+        }));
+    });
+}
+
+fn write_panic_log(report: &PanicReport) {
+    let path = match PANIC_LOG.lock() {
+        Ok(path) => match path.as_ref() {
+            Some(path) => path.clone(),
+            None => return,
+        },
+        Err(_) => return,
+    };
+    if let Some(parent) = path.parent() {
+        let _ = fs::create_dir_all(parent);
+    }
+    if let Ok(mut file) = OpenOptions::new().append(true).create(true).open(path) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs());
+        let _ = writeln!(
+            file,
+            "[{timestamp}] thread '{}' panicked at {}:\n{}\nstack backtrace:\n{}",
+            report.thread, report.location, report.message, report.backtrace
+        );
+    }
+}
+
+/// Runs the body of an export, turning a panic into an error for BYOND rather
+/// than unwinding across the FFI boundary.
+pub fn catch_panic<R: IntoReturn, F: FnOnce() -> R>(f: F) -> Option<Vec<u8>> {
+    install_panic_hook();
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(value) => value.into_return(),
+        Err(_) => Some(take_panic_reply().into_bytes()),
+    }
+}
+
+/// Renders the panic this thread just caught, as the error BYOND gets back
+/// in place of the result.
+fn take_panic_reply() -> String {
+    let report = THREAD_PANIC.with(|cell| cell.take());
+    if envelope_enabled() {
+        let message = report.map_or_else(String::new, |report| {
+            format!("{} at {}", report.message, report.location)
+        });
+        envelope_err("panic", &message)
+    } else {
+        report.map_or_else(|| "{\"panic\":\"\"}".to_owned(), |report| report.to_json())
+    }
+}
+
+/// Anything an export body can evaluate to.
+pub trait IntoReturn {
+    fn into_return(self) -> Option<Vec<u8>>;
+}
+
+impl<T: Into<Vec<u8>>> IntoReturn for Option<T> {
+    fn into_return(self) -> Option<Vec<u8>> {
+        let value = self.map(Into::into);
+        if !envelope_enabled() {
+            return value;
+        }
+        let value = value.map(|value| String::from_utf8_lossy(&value).into_owned());
+        Some(envelope_ok(value.as_deref()).into_bytes())
+    }
+}
+
+impl IntoReturn for Reply {
+    fn into_return(self) -> Option<Vec<u8>> {
+        self.0
+    }
+}
+
+/// Quotes and escapes a string for inclusion in hand-written JSON.
+pub fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
         }
+    }
+    out.push('"');
+    out
+}
+
+/// Deserializes a number sent from DM as a bool, since DM has no booleans of
+/// its own: 0 is false, anything else is true.
+#[cfg(any(feature = "acreplace", feature = "log"))]
+pub fn deserialize_byond_bool<'de, D>(deserializer: D) -> std::result::Result<bool, D::Error>
+where
+    D: serde::de::Deserializer<'de>,
+{
+    match <u8 as serde::Deserialize>::deserialize(deserializer)? {
+        0 => Ok(false),
+        _ => Ok(true),
+    }
+}
+
+// Sets the file panics are appended to, with their backtraces. Empty disables it.
+byond_fn!(fn panic_set_log(path) {
+    Reply::error_or_none(set_panic_log(path))
+});
+
+fn set_panic_log(path: &str) -> Result<()> {
+    let path = match path {
+        "" => None,
+        path => Some(sandbox::check(path)?),
     };
+    *PANIC_LOG.lock().map_err(|_| Error::Poisoned)? = path;
+    Ok(())
 }
 
+// The most recent panic on any thread, including job workers, as JSON.
+byond_fn!(
+    fn panic_last() {
+        LAST_PANIC.lock().ok()?.clone()
+    }
+);
+
 // Easy version checker. It's in this file so it is always included
 byond_fn!(
     fn get_version() {
         Some(env!("CARGO_PKG_VERSION"))
     }
 );
+
+// The enabled features and other build metadata, as JSON. Put together by `build.rs`.
+byond_fn!(
+    fn get_features() {
+        Some(env!("RUSTG_BUILD_INFO"))
+    }
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ENVELOPE_LOCK;
+
+    type Export = unsafe extern "C" fn(c_int, *const *const c_char) -> *const c_char;
+
+    fn call(export: Export, args: &[&str]) -> String {
+        let args: Vec<CString> = args.iter().map(|arg| CString::new(*arg).unwrap()).collect();
+        let argv: Vec<*const c_char> = args.iter().map(|arg| arg.as_ptr()).collect();
+        let value = unsafe { CStr::from_ptr(export(argv.len() as c_int, argv.as_ptr())) };
+        value.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn panics_become_json_errors() {
+        let _lock = ENVELOPE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let ok = catch_panic(|| Some("fine"));
+        assert_eq!(ok.as_deref(), Some(&b"fine"[..]));
+
+        let err = catch_panic(|| -> Option<String> { panic!("bad \"input\"") }).unwrap();
+        let err = String::from_utf8(err).unwrap();
+        assert!(err.starts_with(r#"{"panic":"bad \"input\"","location":"src/byond.rs:"#));
+    }
+
+    #[test]
+    fn native_panics_become_null() {
+        assert_eq!(catch_panic_native(|| Some(1.5)).as_number(), Some(1.5));
+        assert_eq!(catch_panic_native(|| None).as_number(), None);
+        assert_eq!(catch_panic_native(|| panic!("bad")).as_number(), None);
+    }
+
+    #[cfg(feature = "jobs")]
+    #[test]
+    fn failed_exports_fail_in_envelopes() {
+        use crate::{error::error_set_envelope, jobs::job_pool_set_size};
+
+        let _lock = ENVELOPE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        assert_eq!(
+            call(job_pool_set_size, &["0"]),
+            "Pool size must be at least 1."
+        );
+        call(error_set_envelope, &["1"]);
+        let reply = call(job_pool_set_size, &["0"]);
+        call(error_set_envelope, &["0"]);
+        assert_eq!(
+            reply,
+            r#"{"ok":false,"value":null,"error":{"kind":"invalid_pool_size","message":"Pool size must be at least 1."}}"#
+        );
+    }
+}
@@ -0,0 +1,299 @@
+//! Evaluates cron expressions, so recurring tasks can be configured as text
+//! and polled for rather than scheduled by hand in DM.
+use crate::{
+    error::{Error, Reply, Result},
+    time::{self, Timezone},
+};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, Timelike, Utc};
+use std::{cell::RefCell, collections::BTreeMap, str::FromStr};
+
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+// Enough to find the next February 29th, even across a century.
+const SEARCH_YEARS: i32 = 9;
+const MAX_NEXT: usize = 1000;
+
+thread_local! {
+    static SCHEDULES: RefCell<BTreeMap<String, Registered>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+struct Registered {
+    schedule: Schedule,
+    timezone: Timezone,
+    next: Option<DateTime<Utc>>,
+}
+
+// Returns the next `count` times the expression fires after the timestamp, or
+// after now if it's empty, as a JSON array of timestamps.
+byond_fn!(fn cron_next(expression, timestamp, count, timezone) {
+    Reply::value_or_none((|| {
+        let schedule: Schedule = expression.parse()?;
+        let timezone: Timezone = timezone.parse()?;
+        let count = if count.is_empty() { 1 } else { count.parse::<usize>()?.min(MAX_NEXT) };
+        let mut time = if timestamp.is_empty() { Utc::now() } else { time::from_timestamp(timestamp)? };
+        let mut times = Vec::new();
+        while times.len() < count {
+            let Some(next) = schedule.next_after(time, timezone) else {
+                break;
+            };
+            times.push(time::to_timestamp(next));
+            time = next;
+        }
+        Ok(serde_json::Value::from(times).to_string())
+    })())
+});
+
+// Registers a schedule under a name, replacing any of the same name. It's first
+// due the next time the expression fires.
+byond_fn!(fn cron_schedule(name, expression, timezone) {
+    Reply::error_or_none((|| {
+        let schedule: Schedule = expression.parse()?;
+        let timezone: Timezone = timezone.parse()?;
+        let next = schedule.next_after(Utc::now(), timezone);
+        SCHEDULES.with(|schedules| {
+            let registered = Registered { schedule, timezone, next };
+            schedules.borrow_mut().insert(name.to_owned(), registered)
+        });
+        Ok(())
+    })())
+});
+
+byond_fn!(fn cron_unschedule(name) {
+    SCHEDULES.with(|schedules| schedules.borrow_mut().remove(name));
+    Some("")
+});
+
+byond_fn!(
+    fn cron_unschedule_all() {
+        SCHEDULES.with(|schedules| schedules.borrow_mut().clear());
+        Some("")
+    }
+);
+
+byond_fn!(
+    fn cron_poll() {
+        Some(serde_json::Value::from(poll(Utc::now())).to_string())
+    }
+);
+
+/// The names of the schedules which have fired since the last poll. A schedule
+/// which fired more than once is only listed once.
+fn poll(now: DateTime<Utc>) -> Vec<String> {
+    SCHEDULES.with(|schedules| {
+        let mut due = Vec::new();
+        for (name, registered) in schedules.borrow_mut().iter_mut() {
+            if registered.next.is_some_and(|next| next <= now) {
+                due.push(name.clone());
+                registered.next = registered.schedule.next_after(now, registered.timezone);
+            }
+        }
+        due
+    })
+}
+
+/// A standard five field cron expression: minute, hour, day of month, month
+/// and day of week. Each field is a bitset of the values it matches.
+struct Schedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // As in Vixie cron, if both day fields are restricted a day matching
+    // either will do, rather than having to match both.
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl FromStr for Schedule {
+    type Err = Error;
+
+    fn from_str(expression: &str) -> Result<Self> {
+        let expression = match expression.trim() {
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            "@monthly" => "0 0 1 * *",
+            "@weekly" => "0 0 * * 0",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@hourly" => "0 * * * *",
+            expression => expression,
+        };
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(Error::InvalidCron(format!(
+                "expected 5 fields, found {}",
+                fields.len()
+            )));
+        };
+        // Sunday is both 0 and 7.
+        let weekdays = parse_field(weekday, 0, 7, &WEEKDAYS)?;
+        Ok(Self {
+            minutes: parse_field(minute, 0, 59, &[])?,
+            hours: parse_field(hour, 0, 23, &[])?,
+            days: parse_field(day, 1, 31, &[])?,
+            months: parse_field(month, 1, 12, &MONTHS)?,
+            weekdays: (weekdays | weekdays >> 7) & 0x7f,
+            any_day: day.starts_with('*'),
+            any_weekday: weekday.starts_with('*'),
+        })
+    }
+}
+
+impl Schedule {
+    /// The first time after `after` which this matches, searching in the
+    /// timezone's local time. Local times skipped by a clock change never fire.
+    fn next_after(&self, after: DateTime<Utc>, timezone: Timezone) -> Option<DateTime<Utc>> {
+        let start = timezone.localize(after);
+        let mut local =
+            start.date().and_hms_opt(start.hour(), start.minute(), 0)? + Duration::minutes(1);
+        while local.year() <= start.year() + SEARCH_YEARS {
+            if !matches(self.months, local.month()) {
+                local = next_month(local)?;
+            } else if !self.matches_day(local.date()) {
+                local = local.date().succ_opt()?.and_hms_opt(0, 0, 0)?;
+            } else if !matches(self.hours, local.hour()) {
+                local = local.date().and_hms_opt(local.hour(), 0, 0)? + Duration::hours(1);
+            } else if !matches(self.minutes, local.minute()) {
+                local += Duration::minutes(1);
+            } else {
+                match timezone.resolve(local) {
+                    Some(time) if time > after => return Some(time),
+                    _ => local += Duration::minutes(1),
+                }
+            }
+        }
+        None
+    }
+
+    fn matches_day(&self, date: NaiveDate) -> bool {
+        let day = matches(self.days, date.day());
+        let weekday = matches(self.weekdays, date.weekday().num_days_from_sunday());
+        if self.any_day || self.any_weekday {
+            day && weekday
+        } else {
+            day || weekday
+        }
+    }
+}
+
+fn matches(bits: u64, value: u32) -> bool {
+    bits & (1 << value) != 0
+}
+
+fn next_month(local: NaiveDateTime) -> Option<NaiveDateTime> {
+    let (year, month) = match local.month() {
+        12 => (local.year() + 1, 1),
+        month => (local.year(), month + 1),
+    };
+    NaiveDate::from_ymd_opt(year, month, 1)?.and_hms_opt(0, 0, 0)
+}
+
+/// Parses a comma separated list of `*`, values and ranges, each optionally
+/// with a `/step`, into a bitset. `names` stand in for the values from `min`.
+fn parse_field(field: &str, min: u32, max: u32, names: &[&str]) -> Result<u64> {
+    let invalid = || Error::InvalidCron(format!("invalid field {field:?}"));
+    let value = |text: &str| -> Result<u32> {
+        match names
+            .iter()
+            .position(|name| name.eq_ignore_ascii_case(text))
+        {
+            Some(index) => Ok(index as u32 + min),
+            None => text.parse().map_err(|_| invalid()),
+        }
+    };
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, Some(step.parse::<usize>().map_err(|_| invalid())?)),
+            None => (part, None),
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (value(start)?, value(end)?),
+            // `5/15` means every 15 starting from 5.
+            None if step.is_some() => (value(range)?, max),
+            None => (value(range)?, value(range)?),
+        };
+        if start < min || end > max || start > end || step == Some(0) {
+            return Err(invalid());
+        }
+        for value in (start..=end).step_by(step.unwrap_or(1)) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn next(expression: &str, after: &str) -> Vec<String> {
+        let schedule: Schedule = expression.parse().unwrap();
+        let mut time = DateTime::parse_from_rfc3339(after)
+            .unwrap()
+            .with_timezone(&Utc);
+        (0..3)
+            .map(|_| {
+                time = schedule.next_after(time, Timezone::Utc).unwrap();
+                time.format("%F %R %a").to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn next_fire_times() {
+        assert_eq!(
+            next("*/20 9-10 * * mon-fri", "2024-03-08T10:30:00Z"),
+            [
+                "2024-03-08 10:40 Fri",
+                "2024-03-11 09:00 Mon",
+                "2024-03-11 09:20 Mon"
+            ]
+        );
+        assert_eq!(
+            next("0 0 29 feb *", "2024-03-01T00:00:00Z"),
+            [
+                "2028-02-29 00:00 Tue",
+                "2032-02-29 00:00 Sun",
+                "2036-02-29 00:00 Fri"
+            ]
+        );
+        // Both day fields restricted: the 1st, or any Sunday.
+        assert_eq!(
+            next("30 4 1 * 7", "2024-03-29T00:00:00Z"),
+            [
+                "2024-03-31 04:30 Sun",
+                "2024-04-01 04:30 Mon",
+                "2024-04-07 04:30 Sun"
+            ]
+        );
+        assert!("61 * * * *".parse::<Schedule>().is_err());
+        assert!("* * * *".parse::<Schedule>().is_err());
+        assert!("*/0 * * * *".parse::<Schedule>().is_err());
+    }
+
+    #[test]
+    fn polling_reports_due_schedules_once() {
+        let start = DateTime::parse_from_rfc3339("2024-03-08T10:30:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let schedule: Schedule = "@hourly".parse().unwrap();
+        let next = schedule.next_after(start, Timezone::Utc);
+        SCHEDULES.with(|schedules| {
+            let registered = Registered {
+                schedule,
+                timezone: Timezone::Utc,
+                next,
+            };
+            schedules
+                .borrow_mut()
+                .insert("restart".to_owned(), registered)
+        });
+
+        assert!(poll(start + Duration::minutes(20)).is_empty());
+        assert_eq!(poll(start + Duration::hours(3)), ["restart"]);
+        assert!(poll(start + Duration::hours(3)).is_empty());
+    }
+}
@@ -1,4 +1,7 @@
-use crate::error::{Error, Result};
+use crate::{
+    error::{Error, Reply, Result},
+    sandbox,
+};
 use dmi::icon::Icon;
 use png::{Decoder, Encoder, OutputInfo, Reader};
 use std::{
@@ -8,11 +11,11 @@ use std::{
 };
 
 byond_fn!(fn dmi_strip_metadata(path) {
-    strip_metadata(path).err()
+    Reply::error_or_none(strip_metadata(path))
 });
 
 byond_fn!(fn dmi_create_png(path, width, height, data) {
-    create_png(path, width, height, data).err()
+    Reply::error_or_none(create_png(path, width, height, data))
 });
 
 byond_fn!(fn dmi_resize_png(path, width, height, resizetype) {
@@ -24,19 +27,20 @@ byond_fn!(fn dmi_resize_png(path, width, height, resizetype) {
         "triangle" => image::imageops::Triangle,
         _ => image::imageops::Nearest,
     };
-    resize_png(path, width, height, resizetype).err()
+    Reply::error_or_none(resize_png(path, width, height, resizetype))
 });
 
 byond_fn!(fn dmi_icon_states(path) {
-    read_states(path).ok()
+    Reply::value_or_none(read_states(path))
 });
 
 fn strip_metadata(path: &str) -> Result<()> {
-    let (reader, frame_info, image) = read_png(path)?;
-    write_png(path, &reader, &frame_info, &image, true)
+    let path = sandbox::check(path)?;
+    let (reader, frame_info, image) = read_png(&path)?;
+    write_png(&path, &reader, &frame_info, &image, true)
 }
 
-fn read_png(path: &str) -> Result<(Reader<File>, OutputInfo, Vec<u8>)> {
+fn read_png(path: &Path) -> Result<(Reader<File>, OutputInfo, Vec<u8>)> {
     let mut reader = Decoder::new(File::open(path)?).read_info()?;
     let mut buf = vec![0; reader.output_buffer_size()];
     let frame_info = reader.next_frame(&mut buf)?;
@@ -45,7 +49,7 @@ fn read_png(path: &str) -> Result<(Reader<File>, OutputInfo, Vec<u8>)> {
 }
 
 fn write_png(
-    path: &str,
+    path: &Path,
     reader: &Reader<File>,
     info: &OutputInfo,
     image: &[u8],
@@ -90,7 +94,8 @@ fn create_png(path: &str, width: &str, height: &str, data: &str) -> Result<()> {
         }
     }
 
-    if let Some(fdir) = Path::new(path).parent() {
+    let path = sandbox::check(path)?;
+    if let Some(fdir) = path.parent() {
         if !fdir.is_dir() {
             create_dir_all(fdir)?;
         }
@@ -112,18 +117,19 @@ fn resize_png<P: AsRef<Path>>(
     let width = width.parse::<u32>()?;
     let height = height.parse::<u32>()?;
 
-    let img = image::open(path.as_ref())?;
+    let path = sandbox::check(path)?;
+    let img = image::open(&path)?;
 
     let newimg = img.resize(width, height, resizetype);
 
-    Ok(newimg.save_with_format(path.as_ref(), image::ImageFormat::Png)?)
+    Ok(newimg.save_with_format(path, image::ImageFormat::Png)?)
 }
 
 /// Output is a JSON string for reading within BYOND
 ///
 /// Erroring at any point will produce an empty string
 fn read_states(path: &str) -> Result<String> {
-    let reader = BufReader::new(File::open(path)?);
+    let reader = BufReader::new(File::open(sandbox::check(path)?)?);
     let icon = Icon::load(reader).ok();
     if icon.is_none() {
         return Err(Error::InvalidPngData);
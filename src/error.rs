@@ -3,6 +3,7 @@ use std::{
     num::{ParseFloatError, ParseIntError},
     result,
     str::Utf8Error,
+    sync::atomic::{AtomicBool, Ordering},
 };
 use thiserror::Error;
 
@@ -28,13 +29,21 @@ pub enum Error {
     Io(#[from] io::Error),
     #[error("Invalid algorithm specified.")]
     InvalidAlgorithm,
+    #[error("Shared state is unusable after a panic while it was locked.")]
+    Poisoned,
     #[cfg(feature = "png")]
     #[error(transparent)]
     ImageDecoding(#[from] DecodingError),
     #[cfg(feature = "png")]
     #[error(transparent)]
     ImageEncoding(#[from] EncodingError),
-    #[cfg(feature = "http")]
+    #[cfg(any(
+        feature = "acreplace",
+        feature = "file",
+        feature = "http",
+        feature = "jobs",
+        feature = "log"
+    ))]
     #[error(transparent)]
     JsonSerialization(#[from] serde_json::Error),
     #[error(transparent)]
@@ -58,9 +67,141 @@ pub enum Error {
     #[cfg(feature = "unzip")]
     #[error(transparent)]
     Unzip(#[from] ZipError),
-    #[cfg(feature = "hash")]
+    #[cfg(any(feature = "hash", feature = "buffer"))]
     #[error("Unable to decode hex value.")]
     HexDecode,
+    #[cfg(any(feature = "hash", feature = "buffer"))]
+    #[error(transparent)]
+    Base64Decode(#[from] base64::DecodeError),
+    #[cfg(feature = "buffer")]
+    #[error("No buffer exists with that handle.")]
+    InvalidBuffer,
+    #[cfg(feature = "sql")]
+    #[error("{0}")]
+    Sql(String),
+    #[cfg(feature = "file")]
+    #[error(transparent)]
+    Glob(#[from] glob::PatternError),
+    #[cfg(feature = "file")]
+    #[error("Unsupported encoding.")]
+    InvalidEncoding,
+    #[cfg(feature = "filewatch")]
+    #[error(transparent)]
+    Watch(#[from] notify::Error),
+    #[cfg(feature = "time")]
+    #[error(transparent)]
+    TimeParse(#[from] chrono::ParseError),
+    #[cfg(feature = "time")]
+    #[error("Invalid timezone specified.")]
+    InvalidTimezone,
+    #[cfg(feature = "time")]
+    #[error("Invalid time format specified.")]
+    InvalidTimeFormat,
+    #[cfg(feature = "time")]
+    #[error("Invalid time unit specified.")]
+    InvalidTimeUnit,
+    #[cfg(feature = "time")]
+    #[error("Time out of range.")]
+    TimeOutOfRange,
+    #[cfg(feature = "time")]
+    #[error("No timer with that ID has been started.")]
+    UnknownTimer,
+    #[cfg(feature = "cron")]
+    #[error("Invalid cron expression: {0}")]
+    InvalidCron(String),
+    #[cfg(feature = "jobs")]
+    #[error("Pool size must be at least 1.")]
+    InvalidPoolSize,
+    #[cfg(any(feature = "http", feature = "sql"))]
+    #[error("Invalid timeout: {0}")]
+    InvalidTimeout(#[from] std::time::TryFromFloatSecsError),
+    #[cfg(feature = "acreplace")]
+    #[error("No replacements are set up with that key.")]
+    InvalidReplacements,
+    #[cfg(any(feature = "redis_pubsub", feature = "redis_reliablequeue"))]
+    #[error("{0}")]
+    Redis(String),
+    #[cfg(feature = "git")]
+    #[error("{0}")]
+    Git(String),
+}
+
+impl Error {
+    /// A stable, machine-readable name for this kind of error, used by the
+    /// JSON envelope. These are mirrored as `RUSTG_ERROR_*` in `main.dm`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Null => "null",
+            Self::Utf8 { .. } => "utf8",
+            Self::InvalidFilename => "invalid_filename",
+            Self::Io(_) => "io",
+            Self::InvalidAlgorithm => "invalid_algorithm",
+            Self::Poisoned => "poisoned",
+            #[cfg(feature = "png")]
+            Self::ImageDecoding(_) => "image_decoding",
+            #[cfg(feature = "png")]
+            Self::ImageEncoding(_) => "image_encoding",
+            #[cfg(any(
+                feature = "acreplace",
+                feature = "file",
+                feature = "http",
+                feature = "jobs",
+                feature = "log"
+            ))]
+            Self::JsonSerialization(_) => "json",
+            Self::ParseInt(_) => "parse_int",
+            Self::ParseFloat(_) => "parse_float",
+            Self::GenericImage(_) => "image",
+            #[cfg(feature = "png")]
+            Self::InvalidPngData => "invalid_png_data",
+            #[cfg(feature = "http")]
+            Self::Request(_) => "request",
+            #[cfg(feature = "toml")]
+            Self::TomlDeserialization(_) => "toml_deserialization",
+            #[cfg(feature = "toml")]
+            Self::TomlSerialization(_) => "toml_serialization",
+            #[cfg(feature = "unzip")]
+            Self::Unzip(_) => "unzip",
+            #[cfg(any(feature = "hash", feature = "buffer"))]
+            Self::HexDecode => "hex_decode",
+            #[cfg(any(feature = "hash", feature = "buffer"))]
+            Self::Base64Decode(_) => "base64_decode",
+            #[cfg(feature = "buffer")]
+            Self::InvalidBuffer => "invalid_buffer",
+            #[cfg(feature = "sql")]
+            Self::Sql(_) => "sql",
+            #[cfg(feature = "file")]
+            Self::Glob(_) => "glob",
+            #[cfg(feature = "file")]
+            Self::InvalidEncoding => "invalid_encoding",
+            #[cfg(feature = "filewatch")]
+            Self::Watch(_) => "watch",
+            #[cfg(feature = "time")]
+            Self::TimeParse(_) => "time_parse",
+            #[cfg(feature = "time")]
+            Self::InvalidTimezone => "invalid_timezone",
+            #[cfg(feature = "time")]
+            Self::InvalidTimeFormat => "invalid_time_format",
+            #[cfg(feature = "time")]
+            Self::InvalidTimeUnit => "invalid_time_unit",
+            #[cfg(feature = "time")]
+            Self::TimeOutOfRange => "time_out_of_range",
+            #[cfg(feature = "time")]
+            Self::UnknownTimer => "unknown_timer",
+            #[cfg(feature = "cron")]
+            Self::InvalidCron(_) => "invalid_cron",
+            #[cfg(feature = "jobs")]
+            Self::InvalidPoolSize => "invalid_pool_size",
+            #[cfg(any(feature = "http", feature = "sql"))]
+            Self::InvalidTimeout(_) => "invalid_timeout",
+            #[cfg(feature = "acreplace")]
+            Self::InvalidReplacements => "invalid_replacements",
+            #[cfg(any(feature = "redis_pubsub", feature = "redis_reliablequeue"))]
+            Self::Redis(_) => "redis",
+            #[cfg(feature = "git")]
+            Self::Git(_) => "git",
+        }
+    }
 }
 
 impl From<Utf8Error> for Error {
@@ -83,3 +224,83 @@ impl From<Error> for Vec<u8> {
         error.to_string().into_bytes()
     }
 }
+
+// ----------------------------------------------------------------------------
+// JSON envelope
+
+static ENVELOPE: AtomicBool = AtomicBool::new(false);
+
+// The envelope mode is global, so tests which depend on it take turns.
+#[cfg(test)]
+pub static ENVELOPE_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+/// Whether exports return `{ok, value, error}` envelopes instead of their
+/// historical, export-specific results.
+pub fn envelope_enabled() -> bool {
+    ENVELOPE.load(Ordering::Relaxed)
+}
+
+pub fn envelope_ok(value: Option<&str>) -> String {
+    let value = value.map_or_else(|| "null".to_owned(), crate::byond::json_string);
+    format!("{{\"ok\":true,\"value\":{value},\"error\":null}}")
+}
+
+pub fn envelope_err(kind: &str, message: &str) -> String {
+    format!(
+        "{{\"ok\":false,\"value\":null,\"error\":{{\"kind\":{},\"message\":{}}}}}",
+        crate::byond::json_string(kind),
+        crate::byond::json_string(message)
+    )
+}
+
+/// The finished return value of a fallible export.
+///
+/// Returned as-is by `byond_fn!`, unlike a plain `Option`, which is wrapped
+/// in a successful envelope when the envelope mode is enabled.
+pub struct Reply(pub Option<Vec<u8>>);
+
+impl Reply {
+    /// Builds the envelope from `result` if the envelope mode is enabled, and
+    /// the export's legacy return value from `legacy` otherwise.
+    pub fn new<F>(result: Result<Option<String>>, legacy: F) -> Self
+    where
+        F: FnOnce(Result<Option<String>>) -> Option<String>,
+    {
+        if !envelope_enabled() {
+            return Self(legacy(result).map(String::into_bytes));
+        }
+        let envelope = match result {
+            Ok(value) => envelope_ok(value.as_deref()),
+            Err(error) => envelope_err(error.kind(), &error.to_string()),
+        };
+        Self(Some(envelope.into_bytes()))
+    }
+
+    /// For exports which historically returned their value, or nothing on failure.
+    pub fn value_or_none<T: Into<String>>(result: Result<T>) -> Self {
+        Self::new(result.map(|value| Some(value.into())), |r| r.ok().flatten())
+    }
+
+    /// For exports which historically returned nothing on success, or the
+    /// error message on failure.
+    pub fn error_or_none<T>(result: Result<T>) -> Self {
+        Self::new(result.map(|_| None), |r| r.err().map(String::from))
+    }
+
+    /// Passes a value through untouched in either mode, e.g. job results
+    /// which were already rendered when the job finished.
+    pub fn raw<T: Into<Vec<u8>>>(value: T) -> Self {
+        Self(Some(value.into()))
+    }
+
+    pub fn into_string(self) -> String {
+        self.0
+            .map(|value| String::from_utf8_lossy(&value).into_owned())
+            .unwrap_or_default()
+    }
+}
+
+byond_fn!(fn error_set_envelope(enabled) {
+    ENVELOPE.store(matches!(enabled, "1" | "true"), Ordering::Relaxed);
+    Some("")
+});
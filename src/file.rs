@@ -1,11 +1,26 @@
-use crate::error::Result;
+use crate::{
+    error::{Error, Reply, Result},
+    sandbox,
+};
 use std::{
-    fs::{File, OpenOptions},
-    io::{BufRead, BufReader, BufWriter, Read, Write},
+    cell::RefCell,
+    collections::HashMap,
+    fs::{self, File, Metadata, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
-byond_fn!(fn file_read(path) {
-    read(path).ok()
+thread_local! {
+    static LINE_INDEXES: RefCell<HashMap<PathBuf, LineIndex>> = RefCell::new(HashMap::new());
+}
+
+// How much of the file `tail` reads at a time, working backwards.
+const TAIL_CHUNK: u64 = 64 * 1024;
+
+byond_fn!(fn file_read(path, encoding, keep_line_endings) {
+    let keep_line_endings = keep_line_endings == "1" || keep_line_endings == "true";
+    Reply::value_or_none(read(path, encoding, keep_line_endings))
 });
 
 byond_fn!(fn file_exists(path) {
@@ -13,46 +28,126 @@ byond_fn!(fn file_exists(path) {
 });
 
 byond_fn!(fn file_write(data, path) {
-    write(data, path).err()
+    Reply::error_or_none(write(data, path))
+});
+
+byond_fn!(fn file_write_atomic(data, path, backups) {
+    let backups = if backups.is_empty() { Ok(0) } else { backups.parse::<usize>() };
+    Reply::error_or_none(backups.map_err(Into::into).and_then(|backups| write_atomic(data, path, backups)))
 });
 
 byond_fn!(fn file_append(data, path) {
-    append(data, path).err()
+    Reply::error_or_none(append(data, path))
 });
 
 byond_fn!(fn file_get_line_count(path) {
-    Some(get_line_count(path).ok()?.to_string())
+    Reply::value_or_none(get_line_count(path).map(|count| count.to_string()))
 });
 
 byond_fn!(fn file_seek_line(path, line) {
-    seek_line(path, match line.parse::<usize>() {
-        Ok(line) => line,
-        Err(_) => return None,
-    })
+    let result = line.parse::<usize>().map_err(Into::into).and_then(|line| seek_line(path, line));
+    Reply::new(result, |r| r.ok().flatten())
 });
 
-fn read(path: &str) -> Result<String> {
-    let file = File::open(path)?;
-    let metadata = file.metadata()?;
-    let mut file = BufReader::new(file);
+byond_fn!(fn file_read_lines(path, start, count) {
+    let result = start
+        .parse::<usize>()
+        .and_then(|start| Ok((start, count.parse::<usize>()?)))
+        .map_err(Into::into)
+        .and_then(|(start, count)| read_lines(path, start, count));
+    Reply::value_or_none(result)
+});
+
+byond_fn!(fn file_tail(path, count) {
+    let result = count.parse::<usize>().map_err(Into::into).and_then(|count| tail(path, count));
+    Reply::value_or_none(result)
+});
 
-    let mut content = String::with_capacity(metadata.len() as usize);
-    file.read_to_string(&mut content)?;
-    let content = content.replace('\r', "");
+byond_fn!(fn file_index_lines(path) {
+    Reply::value_or_none(index_lines(path).map(|count| count.to_string()))
+});
+
+byond_fn!(fn file_unindex_lines(path) {
+    let result = sandbox::check(path).map(|path| {
+        LINE_INDEXES.with(|indexes| indexes.borrow_mut().remove(&path));
+    });
+    Reply::error_or_none(result)
+});
+
+byond_fn!(fn file_list_dir(path, pattern, recursive) {
+    let recursive = recursive == "1" || recursive == "true";
+    let result = list_dir(path, pattern, recursive).map(Some);
+    Reply::new(result, |r| r.unwrap_or_else(|e| Some(e.to_string())))
+});
+
+byond_fn!(fn file_delete(path) {
+    Reply::error_or_none(delete(path))
+});
+
+byond_fn!(fn file_move(from, to) {
+    Reply::error_or_none(move_file(from, to))
+});
+
+byond_fn!(fn file_copy(from, to) {
+    Reply::error_or_none(copy(from, to))
+});
+
+byond_fn!(fn file_mkdir(path) {
+    Reply::error_or_none(sandbox::check(path).and_then(|path| Ok(fs::create_dir_all(path)?)))
+});
+
+fn read(path: &str, encoding: &str, keep_line_endings: bool) -> Result<String> {
+    let content = decode(fs::read(sandbox::check(path)?)?, encoding)?;
+    if keep_line_endings {
+        Ok(content)
+    } else {
+        Ok(content.replace('\r', ""))
+    }
+}
 
-    Ok(content)
+/// Decodes the contents of a file. UTF-8 is the default, and is the only
+/// encoding which fails on invalid input rather than substituting U+FFFD.
+/// For Windows-1252 and UTF-16, a byte order mark takes precedence over the
+/// requested encoding. UTF-8 only skips its own BOM, so a UTF-16 one is an
+/// error, and Latin-1 treats any BOM as more text.
+fn decode(mut bytes: Vec<u8>, encoding: &str) -> Result<String> {
+    let encoding = match encoding.to_ascii_lowercase().as_str() {
+        "" | "utf-8" | "utf8" => {
+            if bytes.starts_with(b"\xEF\xBB\xBF") {
+                bytes.drain(..3);
+            }
+            return Ok(String::from_utf8(bytes).map_err(|e| e.utf8_error())?);
+        }
+        // encoding_rs treats this as Windows-1252, which differs in 0x80-0x9F.
+        "latin1" | "latin-1" | "iso-8859-1" => {
+            return Ok(bytes.into_iter().map(char::from).collect());
+        }
+        "cp1252" | "windows-1252" => encoding_rs::WINDOWS_1252,
+        // Little endian unless the BOM says otherwise, as Windows writes it.
+        "utf-16" | "utf16" | "utf-16le" => encoding_rs::UTF_16LE,
+        "utf-16be" => encoding_rs::UTF_16BE,
+        _ => return Err(Error::InvalidEncoding),
+    };
+    let (content, _, _) = encoding.decode(&bytes);
+    Ok(content.into_owned())
 }
 
 fn exists(path: &str) -> String {
-    let path = std::path::Path::new(path);
-    path.exists().to_string()
+    sandbox::check(path)
+        .is_ok_and(|path| path.exists())
+        .to_string()
 }
 
-fn write(data: &str, path: &str) -> Result<usize> {
-    let path: &std::path::Path = path.as_ref();
+fn create_parent(path: &Path) -> Result<()> {
     if let Some(parent) = path.parent() {
-        std::fs::create_dir_all(parent)?;
+        fs::create_dir_all(parent)?;
     }
+    Ok(())
+}
+
+fn write(data: &str, path: &str) -> Result<usize> {
+    let path = sandbox::check(path)?;
+    create_parent(&path)?;
 
     let mut file = BufWriter::new(File::create(path)?);
     let written = file.write(data.as_bytes())?;
@@ -66,10 +161,8 @@ fn write(data: &str, path: &str) -> Result<usize> {
 }
 
 fn append(data: &str, path: &str) -> Result<usize> {
-    let path: &std::path::Path = path.as_ref();
-    if let Some(parent) = path.parent() {
-        std::fs::create_dir_all(parent)?;
-    }
+    let path = sandbox::check(path)?;
+    create_parent(&path)?;
 
     let mut file = BufWriter::new(OpenOptions::new().append(true).create(true).open(path)?);
     let written = file.write(data.as_bytes())?;
@@ -82,12 +175,433 @@ fn append(data: &str, path: &str) -> Result<usize> {
     Ok(written)
 }
 
+/// Writes to a temporary file next to `path` and renames it over `path`, so
+/// readers see either the old contents or the new ones, never a partial write.
+/// The previous version is kept as `<path>.bak`, with older ones shifted to
+/// `<path>.bak.1` and so on, up to `backups` files in all.
+fn write_atomic(data: &str, path: &str, backups: usize) -> Result<()> {
+    let path = sandbox::check(path)?;
+    create_parent(&path)?;
+
+    let mut temp_name = path.file_name().unwrap_or_default().to_owned();
+    temp_name.push(format!(".{}.tmp", std::process::id()));
+    let temp = path.with_file_name(temp_name);
+
+    let result = (|| -> Result<()> {
+        let mut file = File::create(&temp)?;
+        file.write_all(data.as_bytes())?;
+        file.sync_all()?;
+        if backups > 0 && path.exists() {
+            rotate_backups(&path, backups)?;
+        }
+        fs::rename(&temp, &path)?;
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result?;
+
+    // Make the rename itself durable. Directories can't be opened like this on Windows.
+    #[cfg(unix)]
+    if let Some(parent) = path.parent() {
+        let _ = File::open(parent).and_then(|dir| dir.sync_all());
+    }
+    Ok(())
+}
+
+fn rotate_backups(path: &Path, backups: usize) -> Result<()> {
+    let backup = |n: usize| {
+        let mut name = path.as_os_str().to_owned();
+        name.push(".bak");
+        if n > 0 {
+            name.push(format!(".{n}"));
+        }
+        PathBuf::from(name)
+    };
+    for n in (1..backups).rev() {
+        if backup(n - 1).exists() {
+            fs::rename(backup(n - 1), backup(n))?;
+        }
+    }
+    // Copied rather than moved, so `path` never goes missing.
+    fs::copy(path, backup(0))?;
+    Ok(())
+}
+
 fn get_line_count(path: &str) -> Result<u32> {
-    let file = BufReader::new(File::open(path)?);
+    let file = BufReader::new(File::open(sandbox::check(path)?)?);
     Ok(file.lines().count() as u32)
 }
 
-fn seek_line(path: &str, line: usize) -> Option<String> {
-    let file = BufReader::new(File::open(path).ok()?);
-    file.lines().nth(line).and_then(std::result::Result::ok)
+fn seek_line(path: &str, line: usize) -> Result<Option<String>> {
+    let path = sandbox::check(path)?;
+    let mut file = BufReader::new(File::open(&path)?);
+    if let Some(offset) = indexed_offset(&path, file.get_ref(), line)? {
+        file.seek(SeekFrom::Start(offset))?;
+        let mut text = String::new();
+        if file.read_line(&mut text)? == 0 {
+            return Ok(None);
+        }
+        return Ok(Some(trim_newline(&text).to_owned()));
+    }
+    Ok(file.lines().nth(line).transpose()?)
+}
+
+/// Byte offsets of the start of every line of a file, so seeks don't have to
+/// scan from the beginning. Only trusted while the file's size and
+/// modification time are unchanged.
+struct LineIndex {
+    len: u64,
+    modified: Option<SystemTime>,
+    offsets: Vec<u64>,
+}
+
+impl LineIndex {
+    fn is_current(&self, metadata: &Metadata) -> bool {
+        self.len == metadata.len() && self.modified == metadata.modified().ok()
+    }
+}
+
+fn index_lines(path: &str) -> Result<usize> {
+    let path = sandbox::check(path)?;
+    let file = File::open(&path)?;
+    let metadata = file.metadata()?;
+    let mut file = BufReader::new(file);
+
+    let mut offsets = Vec::new();
+    let mut offset = 0;
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let read = file.read_until(b'\n', &mut line)? as u64;
+        if read == 0 {
+            break;
+        }
+        offsets.push(offset);
+        offset += read;
+    }
+
+    let count = offsets.len();
+    let index = LineIndex {
+        len: metadata.len(),
+        modified: metadata.modified().ok(),
+        offsets,
+    };
+    LINE_INDEXES.with(|indexes| indexes.borrow_mut().insert(path, index));
+    Ok(count)
+}
+
+/// Where `line` starts according to the index of `path`, if it has an
+/// up-to-date one. Lines past the end start at the end of the file.
+fn indexed_offset(path: &Path, file: &File, line: usize) -> Result<Option<u64>> {
+    let metadata = file.metadata()?;
+    Ok(LINE_INDEXES.with(|indexes| {
+        let indexes = indexes.borrow();
+        let index = indexes
+            .get(path)
+            .filter(|index| index.is_current(&metadata))?;
+        Some(index.offsets.get(line).copied().unwrap_or(index.len))
+    }))
+}
+
+fn trim_newline(line: &str) -> &str {
+    let line = line.strip_suffix('\n').unwrap_or(line);
+    line.strip_suffix('\r').unwrap_or(line)
+}
+
+/// Reads up to `count` lines starting at line `start`, as a JSON array.
+fn read_lines(path: &str, start: usize, count: usize) -> Result<String> {
+    let path = sandbox::check(path)?;
+    let mut file = BufReader::new(File::open(&path)?);
+    let mut line = Vec::new();
+    match indexed_offset(&path, file.get_ref(), start)? {
+        Some(offset) => {
+            file.seek(SeekFrom::Start(offset))?;
+        }
+        None => {
+            for _ in 0..start {
+                line.clear();
+                if file.read_until(b'\n', &mut line)? == 0 {
+                    break;
+                }
+            }
+        }
+    }
+
+    let mut lines = Vec::new();
+    while lines.len() < count {
+        line.clear();
+        if file.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        lines.push(trim_newline(&String::from_utf8_lossy(&line)).to_owned());
+    }
+    Ok(serde_json::to_string(&lines)?)
+}
+
+/// Reads the last `count` lines, as a JSON array, without reading the rest of
+/// the file.
+fn tail(path: &str, count: usize) -> Result<String> {
+    let mut file = File::open(sandbox::check(path)?)?;
+    let len = file.metadata()?.len();
+
+    // Every complete line after the first, partial one needs the newline
+    // before it, plus the newline ending the file if there is one.
+    let mut needed = count;
+    let mut newlines = 0;
+    let mut pos = len;
+    let mut buf = Vec::new();
+    while pos > 0 && newlines < needed {
+        let size = TAIL_CHUNK.min(pos);
+        pos -= size;
+        let mut chunk = vec![0; size as usize];
+        file.seek(SeekFrom::Start(pos))?;
+        file.read_exact(&mut chunk)?;
+        if pos + size == len && chunk.last() == Some(&b'\n') {
+            needed += 1;
+        }
+        newlines += chunk.iter().filter(|&&byte| byte == b'\n').count();
+        chunk.extend_from_slice(&buf);
+        buf = chunk;
+    }
+
+    let text = buf.strip_suffix(b"\n").unwrap_or(&buf);
+    let mut lines: Vec<&[u8]> = if text.is_empty() {
+        Vec::new()
+    } else {
+        text.split(|&byte| byte == b'\n').collect()
+    };
+    if pos > 0 && !lines.is_empty() {
+        lines.remove(0);
+    }
+    let lines: Vec<String> = lines[lines.len().saturating_sub(count)..]
+        .iter()
+        .map(|line| trim_newline(&String::from_utf8_lossy(line)).to_owned())
+        .collect();
+    Ok(serde_json::to_string(&lines)?)
+}
+
+const GLOB_OPTIONS: glob::MatchOptions = glob::MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// Lists the contents of `dir` as JSON. `pattern` is a glob matched against
+/// the path relative to `dir`, so `*.json` only matches at the top level and
+/// `**/*.json` at any depth; empty matches everything.
+fn list_dir(dir: &str, pattern: &str, recursive: bool) -> Result<String> {
+    let pattern = if pattern.is_empty() {
+        None
+    } else {
+        Some(glob::Pattern::new(pattern)?)
+    };
+    let dir = sandbox::check(dir)?;
+    let mut entries = Vec::new();
+    list_dir_into(
+        &dir,
+        Path::new(""),
+        pattern.as_ref(),
+        recursive,
+        &mut entries,
+    )?;
+    Ok(serde_json::Value::Array(entries).to_string())
+}
+
+fn list_dir_into(
+    root: &Path,
+    relative: &Path,
+    pattern: Option<&glob::Pattern>,
+    recursive: bool,
+    entries: &mut Vec<serde_json::Value>,
+) -> Result<()> {
+    let mut dir: Vec<_> = fs::read_dir(root.join(relative))?.collect::<std::io::Result<_>>()?;
+    dir.sort_by_key(|entry| entry.file_name());
+    for entry in dir {
+        // Symlinks are listed but never followed, so a loop can't recurse
+        // forever and a link can't reveal what's outside the sandbox.
+        let file_type = entry.file_type()?;
+        let metadata = fs::symlink_metadata(entry.path())?;
+        let path = relative.join(entry.file_name());
+        // Forward slashes on every platform, like BYOND's own paths.
+        let name = path.to_string_lossy().replace('\\', "/");
+        if pattern.map_or(true, |pattern| pattern.matches_with(&name, GLOB_OPTIONS)) {
+            let mtime = metadata
+                .modified()
+                .ok()
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |duration| duration.as_secs());
+            entries.push(serde_json::json!({
+                "path": name,
+                "size": metadata.len(),
+                "mtime": mtime,
+                "is_dir": file_type.is_dir(),
+                "is_symlink": file_type.is_symlink(),
+            }));
+        }
+        if recursive && file_type.is_dir() {
+            list_dir_into(root, &path, pattern, recursive, entries)?;
+        }
+    }
+    Ok(())
+}
+
+/// Deletes a file, or a directory along with everything in it, like `fdel`.
+fn delete(path: &str) -> Result<()> {
+    let path = sandbox::check(path)?;
+    if fs::symlink_metadata(&path)?.is_dir() {
+        fs::remove_dir_all(path)?;
+    } else {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+fn move_file(from: &str, to: &str) -> Result<()> {
+    let (from, to) = (sandbox::check(from)?, sandbox::check(to)?);
+    create_parent(&to)?;
+    match fs::rename(&from, &to) {
+        // Renaming doesn't work across filesystems, so fall back to copying.
+        Err(e) if e.raw_os_error() == Some(CROSSES_DEVICES) => {
+            fs::copy(&from, &to)?;
+            fs::remove_file(&from)?;
+        }
+        result => result?,
+    }
+    Ok(())
+}
+
+/// The OS error `rename` fails with when moving between filesystems.
+/// `io::ErrorKind::CrossesDevices` isn't stable on our minimum Rust version.
+#[cfg(unix)]
+const CROSSES_DEVICES: i32 = 18; // EXDEV
+#[cfg(windows)]
+const CROSSES_DEVICES: i32 = 17; // ERROR_NOT_SAME_DEVICE
+
+fn copy(from: &str, to: &str) -> Result<()> {
+    let (from, to) = (sandbox::check(from)?, sandbox::check(to)?);
+    create_parent(&to)?;
+    fs::copy(from, to)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn list_dir_filters_and_recurses() {
+        let dir = TempDir::new("list-dir");
+        write("{}", dir.join("a.json").to_str().unwrap()).unwrap();
+        write("", dir.join("b.txt").to_str().unwrap()).unwrap();
+        write("[]", dir.join("sub/c.json").to_str().unwrap()).unwrap();
+        let dir = dir.to_str().unwrap();
+
+        let paths = |pattern, recursive| -> Vec<String> {
+            let listing: serde_json::Value =
+                serde_json::from_str(&list_dir(dir, pattern, recursive).unwrap()).unwrap();
+            listing
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|entry| entry["path"].as_str().unwrap().to_owned())
+                .collect()
+        };
+        assert_eq!(paths("", false), ["a.json", "b.txt", "sub"]);
+        assert_eq!(paths("*.json", true), ["a.json"]);
+        assert_eq!(paths("**/*.json", true), ["a.json", "sub/c.json"]);
+
+        delete(dir).unwrap();
+        assert!(!Path::new(dir).exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn list_dir_skips_symlinked_dirs() {
+        let dir = TempDir::new("list-dir-symlink");
+        write("", dir.join("a.txt").to_str().unwrap()).unwrap();
+        std::os::unix::fs::symlink(&*dir, dir.join("loop")).unwrap();
+        let dir = dir.to_str().unwrap();
+
+        let listing: serde_json::Value =
+            serde_json::from_str(&list_dir(dir, "", true).unwrap()).unwrap();
+        let listing = listing.as_array().unwrap();
+        assert_eq!(listing.len(), 2);
+        assert_eq!(listing[1]["path"], "loop");
+        assert_eq!(listing[1]["is_dir"], false);
+        assert_eq!(listing[1]["is_symlink"], true);
+
+        delete(dir).unwrap();
+        assert!(!Path::new(dir).exists());
+    }
+
+    #[test]
+    fn atomic_writes_rotate_backups() {
+        let dir = TempDir::new("atomic");
+        let target = dir.join("a.json");
+        let target = target.to_str().unwrap();
+        write_atomic("1", target, 2).unwrap();
+        write_atomic("2", target, 2).unwrap();
+        write_atomic("3", target, 2).unwrap();
+        assert_eq!(read(target, "", false).unwrap(), "3");
+        assert_eq!(read(&format!("{target}.bak"), "", false).unwrap(), "2");
+        assert_eq!(read(&format!("{target}.bak.1"), "", false).unwrap(), "1");
+        assert!(!Path::new(&format!("{target}.bak.2")).exists());
+    }
+
+    #[test]
+    fn lines_tail_and_index() {
+        let dir = TempDir::new("lines");
+        let dir = dir.to_str().unwrap();
+        let log = format!("{dir}/log.txt");
+        let text: String = (0..20000).map(|n| format!("line {n}\r\n")).collect();
+        write(&text, &log).unwrap();
+        assert_eq!(tail(&log, 2).unwrap(), r#"["line 19998","line 19999"]"#);
+        assert_eq!(tail(&log, 0).unwrap(), "[]");
+        assert_eq!(read_lines(&log, 5, 2).unwrap(), r#"["line 5","line 6"]"#);
+        assert_eq!(index_lines(&log).unwrap(), 20000);
+        assert_eq!(read_lines(&log, 19999, 5).unwrap(), r#"["line 19999"]"#);
+        assert_eq!(
+            seek_line(&log, 12345).unwrap().as_deref(),
+            Some("line 12345")
+        );
+        assert_eq!(seek_line(&log, 20000).unwrap(), None);
+        write("a\nb", &log).unwrap();
+        assert_eq!(tail(&log, 5).unwrap(), r#"["a","b"]"#);
+        // The index is stale now, so this scans instead.
+        assert_eq!(seek_line(&log, 1).unwrap().as_deref(), Some("b"));
+
+        delete(dir).unwrap();
+        assert!(!Path::new(dir).exists());
+    }
+
+    #[test]
+    fn legacy_encodings() {
+        assert_eq!(
+            decode(b"caf\xe9 \x80".to_vec(), "latin1").unwrap(),
+            "caf\u{e9} \u{80}"
+        );
+        assert_eq!(
+            decode(b"caf\xe9 \x80".to_vec(), "cp1252").unwrap(),
+            "caf\u{e9} \u{20ac}"
+        );
+        assert_eq!(decode(b"\xff\xfeh\0i\0".to_vec(), "utf-16").unwrap(), "hi");
+        assert_eq!(decode(b"\xfe\xff\0h\0i".to_vec(), "utf-16").unwrap(), "hi");
+        assert_eq!(decode(b"\xef\xbb\xbfhi".to_vec(), "utf-8").unwrap(), "hi");
+        assert_eq!(decode(b"\xef\xbb\xbfhi".to_vec(), "").unwrap(), "hi");
+        assert!(matches!(
+            decode(b"caf\xe9".to_vec(), ""),
+            Err(Error::Utf8 { .. })
+        ));
+        assert!(matches!(
+            decode(b"\xff\xfeh\0i\0".to_vec(), "utf-8"),
+            Err(Error::Utf8 { .. })
+        ));
+        assert!(matches!(
+            decode(Vec::new(), "ebcdic"),
+            Err(Error::InvalidEncoding)
+        ));
+    }
 }
@@ -0,0 +1,168 @@
+//! Watches files and directories for changes, so DM can poll for them rather
+//! than re-reading everything on a timer.
+use crate::{
+    error::{Reply, Result},
+    sandbox,
+};
+use notify::{
+    event::{ModifyKind, RenameMode},
+    Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher,
+};
+use std::{cell::RefCell, collections::HashMap, path::PathBuf};
+
+thread_local! {
+    static WATCHER: RefCell<Option<FileWatcher>> = const { RefCell::new(None) };
+}
+
+struct FileWatcher {
+    // The watcher runs its own thread, which stops when this is dropped.
+    watcher: RecommendedWatcher,
+    events: flume::Receiver<notify::Result<Event>>,
+}
+
+byond_fn!(fn file_watch(path, recursive) {
+    let mode = if recursive == "1" || recursive == "true" {
+        RecursiveMode::Recursive
+    } else {
+        RecursiveMode::NonRecursive
+    };
+    Reply::error_or_none(watch(path, mode))
+});
+
+byond_fn!(fn file_unwatch(path) {
+    Reply::error_or_none(unwatch(path))
+});
+
+byond_fn!(
+    fn file_unwatch_all() {
+        WATCHER.with(|cell| cell.replace(None));
+        Some("")
+    }
+);
+
+byond_fn!(
+    fn file_watch_poll() {
+        Some(poll())
+    }
+);
+
+fn watch(path: &str, mode: RecursiveMode) -> Result<()> {
+    let path = sandbox::check(path)?;
+    WATCHER.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let watcher = match cell.as_mut() {
+            Some(watcher) => watcher,
+            None => {
+                let (tx, events) = flume::unbounded();
+                let watcher = notify::recommended_watcher(move |event| {
+                    let _ = tx.send(event);
+                })?;
+                cell.insert(FileWatcher { watcher, events })
+            }
+        };
+        Ok(watcher.watcher.watch(&path, mode)?)
+    })
+}
+
+fn unwatch(path: &str) -> Result<()> {
+    let path = sandbox::check(path)?;
+    WATCHER.with(|cell| match cell.borrow_mut().as_mut() {
+        Some(watcher) => Ok(watcher.watcher.unwatch(&path)?),
+        None => Ok(()),
+    })
+}
+
+/// Drains the events seen since the last poll, as a JSON array of
+/// `{"event": "created"|"modified"|"deleted", "path": ...}`, in the order they
+/// happened. Watch errors come through in-band as `"error"` events.
+fn poll() -> String {
+    let batch = WATCHER.with(|cell| match cell.borrow().as_ref() {
+        Some(watcher) => changes(watcher.events.try_iter()),
+        None => Vec::new(),
+    });
+    serde_json::Value::Array(batch).to_string()
+}
+
+/// Repeats of an event for a path are left out, since a single save tends to
+/// produce several, but only back to back ones, so the last event for each
+/// path still tells whether it exists.
+fn changes(events: impl Iterator<Item = notify::Result<Event>>) -> Vec<serde_json::Value> {
+    let mut last = HashMap::new();
+    let mut batch = Vec::new();
+    for event in events {
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => {
+                batch.push(
+                    serde_json::json!({ "event": "error", "path": "", "message": e.to_string() }),
+                );
+                continue;
+            }
+        };
+        for (kind, path) in classify(event) {
+            if last.get(&path) != Some(&kind) {
+                batch.push(serde_json::json!({ "event": kind, "path": path.to_string_lossy() }));
+                last.insert(path, kind);
+            }
+        }
+    }
+    batch
+}
+
+fn classify(event: Event) -> Vec<(&'static str, PathBuf)> {
+    let kind = match event.kind {
+        EventKind::Create(_) => "created",
+        EventKind::Remove(_) => "deleted",
+        // A rename is the old name going away and the new one appearing.
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+            let mut paths = event.paths.into_iter();
+            return paths
+                .next()
+                .map(|from| ("deleted", from))
+                .into_iter()
+                .chain(paths.next().map(|to| ("created", to)))
+                .collect();
+        }
+        EventKind::Modify(ModifyKind::Name(RenameMode::From)) => "deleted",
+        EventKind::Modify(ModifyKind::Name(RenameMode::To)) => "created",
+        EventKind::Modify(_) => "modified",
+        EventKind::Access(_) | EventKind::Any | EventKind::Other => return Vec::new(),
+    };
+    event.paths.into_iter().map(|path| (kind, path)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::{CreateKind, DataChange, RemoveKind};
+
+    #[test]
+    fn renames_are_a_delete_and_a_create() {
+        let rename = Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::Both)))
+            .add_path("old.toml".into())
+            .add_path("new.toml".into());
+        assert_eq!(
+            classify(rename),
+            [
+                ("deleted", "old.toml".into()),
+                ("created", "new.toml".into())
+            ]
+        );
+        let create = Event::new(EventKind::Create(CreateKind::File)).add_path("a.json".into());
+        assert_eq!(classify(create), [("created", PathBuf::from("a.json"))]);
+    }
+
+    #[test]
+    fn only_back_to_back_repeats_are_collapsed() {
+        let event = |kind| Ok(Event::new(kind).add_path("a.json".into()));
+        let created = || event(EventKind::Create(CreateKind::File));
+        let modified = || event(EventKind::Modify(ModifyKind::Data(DataChange::Any)));
+        let deleted = || event(EventKind::Remove(RemoveKind::File));
+        let kinds: Vec<_> =
+            changes([created(), modified(), modified(), deleted(), created()].into_iter())
+                .into_iter()
+                .map(|change| change["event"].as_str().unwrap().to_owned())
+                .collect();
+        assert_eq!(kinds, ["created", "modified", "deleted", "created"]);
+    }
+}
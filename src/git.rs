@@ -1,26 +1,390 @@
-use chrono::{TimeZone, Utc};
-use gix::{open::Error as OpenError, Repository};
+use crate::{
+    error::{Error, Reply, Result},
+    sandbox,
+};
+use chrono::{SecondsFormat, TimeZone, Utc};
+use gix::{
+    bstr::{BString, ByteSlice},
+    index::entry::{Flags, Mode},
+    objs::tree::EntryMode,
+    open::Error as OpenError,
+    ObjectId, Repository,
+};
+use std::{
+    cell::RefCell,
+    collections::{hash_map::Entry, BTreeMap, BinaryHeap, HashMap},
+    fmt::Display,
+    fs,
+    path::PathBuf,
+};
+
+const DEFAULT_HANDLE: usize = 0;
 
 thread_local! {
-    static REPOSITORY: Result<Repository, OpenError> = gix::open(".");
+    static REPOSITORIES: RefCell<Repositories> = RefCell::new(Repositories::default());
 }
 
-byond_fn!(fn rg_git_revparse(rev) {
-    REPOSITORY.with(|repo| -> Option<String> {
-        let repo = repo.as_ref().ok()?;
-        let object = repo.rev_parse_single(rev).ok()?;
-        Some(object.to_string())
-    })
+// Opens another repository, e.g. a submodule, returning a handle the other
+// exports take as their last argument.
+byond_fn!(fn rg_git_open(path) {
+    Reply::value_or_none((|| {
+        let path = sandbox::check(path)?;
+        let repository = gix::open(&path).map_err(git_error)?;
+        Ok(REPOSITORIES.with(|repos| repos.borrow_mut().insert(path, repository).to_string()))
+    })())
 });
 
-byond_fn!(fn rg_git_commit_date(rev) {
-    REPOSITORY.with(|repo| -> Option<String> {
-        let repo = repo.as_ref().ok()?;
-        let rev = repo.rev_parse_single(rev).ok()?;
-        let object = rev.object().ok()?;
-        let commit = object.try_into_commit().ok()?;
-        let commit_time = commit.committer().ok()?.time;
-        let datetime = Utc.timestamp_opt(commit_time.seconds, 0).latest()?;
-        Some(datetime.format("%F").to_string())
-    })
+// Opens a handle's repository again, e.g. if it didn't exist when first opened.
+// Returns the handle if it succeeds, 0 for the default one.
+byond_fn!(fn rg_git_reopen(handle) {
+    Reply::value_or_none(reopen(handle))
+});
+
+byond_fn!(fn rg_git_close(handle) {
+    Reply::value_or_none(parse_handle(handle).map(|id| {
+        REPOSITORIES.with(|repos| repos.borrow_mut().open.remove(&id));
+        ""
+    }))
+});
+
+byond_fn!(fn rg_git_revparse(rev, handle) {
+    Reply::value_or_none(with_repository(handle, |repo| {
+        let object = repo.rev_parse_single(rev).map_err(git_error)?;
+        Ok(object.to_string())
+    }))
+});
+
+byond_fn!(fn rg_git_commit_date(rev, handle) {
+    Reply::value_or_none(with_repository(handle, |repo| {
+        let rev = repo.rev_parse_single(rev).map_err(git_error)?;
+        let object = rev.object().map_err(git_error)?;
+        let commit = object.try_into_commit().map_err(git_error)?;
+        let commit_time = commit.committer().map_err(git_error)?.time;
+        Ok(timestamp(commit_time.seconds)?.format("%F").to_string())
+    }))
+});
+
+// Returns the commits reachable from `to` but not from `from`, newest first, as
+// JSON. An empty `to` means HEAD, and an empty `from` the whole history.
+byond_fn!(fn rg_git_log(from, to, limit, handle) {
+    Reply::value_or_none(with_repository(handle, |repo| {
+        let limit = if limit.is_empty() { usize::MAX } else { limit.parse()? };
+        Ok(log(repo, from, to, limit)?.to_string())
+    }))
+});
+
+// Returns the files which differ between two revisions as JSON. An empty `to`
+// means HEAD.
+byond_fn!(fn rg_git_diff_files(from, to, handle) {
+    Reply::value_or_none(with_repository(handle, |repo| Ok(diff_files(repo, from, to)?.to_string())))
+});
+
+// Returns 1 if tracked files have changes, staged or not, and 0 otherwise.
+byond_fn!(fn rg_git_dirty(handle) {
+    Reply::value_or_none(with_repository(handle, |repo| Ok(if is_dirty(repo)? { "1" } else { "0" })))
 });
+
+/// Repositories by handle. The empty handle, 0, is the one the server runs
+/// in, which is opened on first use.
+struct Repositories {
+    open: BTreeMap<usize, Handle>,
+    next_id: usize,
+}
+
+struct Handle {
+    path: PathBuf,
+    repository: std::result::Result<Repository, OpenError>,
+}
+
+impl Default for Repositories {
+    fn default() -> Self {
+        Self {
+            open: BTreeMap::new(),
+            next_id: DEFAULT_HANDLE + 1,
+        }
+    }
+}
+
+impl Repositories {
+    fn insert(&mut self, path: PathBuf, repository: Repository) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        let repository = Ok(repository);
+        self.open.insert(id, Handle { path, repository });
+        id
+    }
+
+    fn get_mut(&mut self, id: usize) -> Option<&mut Handle> {
+        if id == DEFAULT_HANDLE {
+            let handle = self.open.entry(id).or_insert_with(|| Handle {
+                path: PathBuf::from("."),
+                repository: gix::open("."),
+            });
+            return Some(handle);
+        }
+        self.open.get_mut(&id)
+    }
+}
+
+fn reopen(handle: &str) -> Result<String> {
+    let id = parse_handle(handle)?;
+    REPOSITORIES.with(|repos| {
+        let mut repos = repos.borrow_mut();
+        let repo = repos.get_mut(id).ok_or_else(no_such_handle)?;
+        repo.repository = gix::open(&repo.path);
+        repo.repository.as_ref().map_err(git_error)?;
+        Ok(id.to_string())
+    })
+}
+
+fn parse_handle(handle: &str) -> Result<usize> {
+    if handle.is_empty() {
+        Ok(DEFAULT_HANDLE)
+    } else {
+        Ok(handle.parse()?)
+    }
+}
+
+fn with_repository<T>(handle: &str, f: impl FnOnce(&Repository) -> Result<T>) -> Result<T> {
+    let id = parse_handle(handle)?;
+    REPOSITORIES.with(|repos| {
+        let mut repos = repos.borrow_mut();
+        let handle = repos.get_mut(id).ok_or_else(no_such_handle)?;
+        let repo = handle.repository.as_ref().map_err(git_error)?;
+        f(repo)
+    })
+}
+
+fn git_error(error: impl Display) -> Error {
+    Error::Git(error.to_string())
+}
+
+fn no_such_handle() -> Error {
+    Error::Git("No repository is open with that handle.".to_owned())
+}
+
+fn rev_id(repo: &Repository, rev: &str) -> Result<ObjectId> {
+    let rev = if rev.is_empty() { "HEAD" } else { rev };
+    Ok(repo.rev_parse_single(rev).map_err(git_error)?.detach())
+}
+
+fn timestamp(seconds: i64) -> Result<chrono::DateTime<Utc>> {
+    Utc.timestamp_opt(seconds, 0)
+        .latest()
+        .ok_or_else(|| Error::Git(format!("Invalid commit time {seconds}.")))
+}
+
+fn log(repo: &Repository, from: &str, to: &str, limit: usize) -> Result<serde_json::Value> {
+    let mut walk = LogWalk::default();
+    walk.push(repo, rev_id(repo, to)?, false)?;
+    if !from.is_empty() {
+        walk.push(repo, rev_id(repo, from)?, true)?;
+    }
+
+    let mut commits = Vec::new();
+    while commits.len() < limit {
+        let Some((id, hidden)) = walk.pop() else {
+            break;
+        };
+        let commit = find_commit(repo, id)?;
+        for parent in commit.parent_ids() {
+            walk.push(repo, parent.detach(), hidden)?;
+        }
+        if hidden {
+            continue;
+        }
+        let author = commit.author().map_err(git_error)?;
+        let date = timestamp(author.time.seconds)?;
+        commits.push(serde_json::json!({
+            "hash": commit.id.to_string(),
+            "author": author.name.to_str_lossy(),
+            "email": author.email.to_str_lossy(),
+            "date": date.to_rfc3339_opts(SecondsFormat::Secs, true),
+            "subject": commit.message().map_err(git_error)?.summary().to_str_lossy(),
+        }));
+    }
+    Ok(commits.into())
+}
+
+/// Walks `to` and `from` together, newest commit first, as `git log from..to`
+/// does. Every child is visited before its parents, so by the time a commit
+/// comes up it's known whether `from` reaches it, and the walk can stop once
+/// only shared history is left instead of going through all of it.
+#[derive(Default)]
+struct LogWalk {
+    queue: BinaryHeap<(i64, ObjectId)>,
+    // Whether each commit seen so far is reachable from `from`, and whether
+    // it's still queued.
+    seen: HashMap<ObjectId, (bool, bool)>,
+    // How many queued commits aren't.
+    visible: usize,
+}
+
+impl LogWalk {
+    fn push(&mut self, repo: &Repository, id: ObjectId, hidden: bool) -> Result<()> {
+        match self.seen.entry(id) {
+            Entry::Occupied(mut entry) => {
+                // A commit which was already listed can only turn out to be
+                // hidden if commit times are skewed, and stays listed then.
+                let (was_hidden, queued) = entry.get_mut();
+                if hidden && !*was_hidden && *queued {
+                    *was_hidden = true;
+                    self.visible -= 1;
+                }
+            }
+            Entry::Vacant(entry) => {
+                let commit = find_commit(repo, id)?;
+                let time = commit.committer().map_err(git_error)?.time.seconds;
+                entry.insert((hidden, true));
+                self.queue.push((time, id));
+                self.visible += usize::from(!hidden);
+            }
+        }
+        Ok(())
+    }
+
+    /// The newest queued commit and whether it's hidden, or `None` once
+    /// every commit left is hidden.
+    fn pop(&mut self) -> Option<(ObjectId, bool)> {
+        if self.visible == 0 {
+            return None;
+        }
+        let (_, id) = self.queue.pop()?;
+        let (hidden, queued) = self.seen.get_mut(&id)?;
+        *queued = false;
+        let hidden = *hidden;
+        self.visible -= usize::from(!hidden);
+        Some((id, hidden))
+    }
+}
+
+fn find_commit(repo: &Repository, id: ObjectId) -> Result<gix::Commit<'_>> {
+    repo.find_object(id)
+        .map_err(git_error)?
+        .try_into_commit()
+        .map_err(git_error)
+}
+
+/// Every file in a revision's tree, by path.
+fn tree_files(repo: &Repository, rev: &str) -> Result<BTreeMap<BString, (EntryMode, ObjectId)>> {
+    let tree = repo
+        .rev_parse_single(rev)
+        .map_err(git_error)?
+        .object()
+        .map_err(git_error)?
+        .peel_to_tree()
+        .map_err(git_error)?;
+    let files = tree.traverse().breadthfirst.files().map_err(git_error)?;
+    Ok(files
+        .into_iter()
+        .filter(|entry| !entry.mode.is_tree())
+        .map(|entry| (entry.filepath, (entry.mode, entry.oid)))
+        .collect())
+}
+
+/// `[{"path", "change": "added"|"deleted"|"modified"}]`, sorted by path.
+fn diff_files(repo: &Repository, from: &str, to: &str) -> Result<serde_json::Value> {
+    let old = tree_files(repo, from)?;
+    let mut new = tree_files(repo, if to.is_empty() { "HEAD" } else { to })?;
+    let mut changes = BTreeMap::new();
+    for (path, entry) in old {
+        match new.remove(&path) {
+            Some(new_entry) if new_entry == entry => {}
+            Some(_) => {
+                changes.insert(path, "modified");
+            }
+            None => {
+                changes.insert(path, "deleted");
+            }
+        }
+    }
+    changes.extend(new.into_keys().map(|path| (path, "added")));
+    let changes: Vec<_> = changes
+        .into_iter()
+        .map(|(path, change)| serde_json::json!({ "path": path.to_str_lossy(), "change": change }))
+        .collect();
+    Ok(changes.into())
+}
+
+/// Whether the index differs from HEAD, or the work tree from the index.
+/// Untracked files don't count, since honouring .gitignore would take a
+/// directory walk.
+fn is_dirty(repo: &Repository) -> Result<bool> {
+    let work_dir = repo
+        .work_dir()
+        .ok_or_else(|| Error::Git("The repository has no work tree.".to_owned()))?;
+    let index = repo.index_or_empty().map_err(git_error)?;
+    let mut head = if repo.head_id().is_ok() {
+        tree_files(repo, "HEAD")?
+    } else {
+        BTreeMap::new()
+    };
+
+    for entry in index.entries() {
+        let path = entry.path(&index);
+        if entry.flags.intersects(Flags::STAGE_MASK) {
+            return Ok(true);
+        }
+        match head.remove(path) {
+            Some((mode, id)) if Mode::from(mode) == entry.mode && id == entry.id => {}
+            _ => return Ok(true),
+        }
+        if entry.mode.is_submodule() || entry.flags.contains(Flags::SKIP_WORKTREE) {
+            continue;
+        }
+
+        let file = work_dir.join(gix::path::from_bstr(path));
+        let Ok(metadata) = fs::symlink_metadata(&file) else {
+            return Ok(true);
+        };
+        if metadata.len() as u32 != entry.stat.size {
+            return Ok(true);
+        }
+        // Unchanged stats mean an unchanged file, unless it was modified so
+        // soon after the index was written that its mtime can't tell.
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|mtime| mtime.try_into().ok());
+        if mtime == Some(entry.stat.mtime)
+            && !entry.stat.is_racy(index.timestamp(), Default::default())
+        {
+            continue;
+        }
+        let data = if entry.mode == Mode::SYMLINK {
+            gix::path::into_bstr(fs::read_link(&file)?)
+                .into_owned()
+                .into()
+        } else {
+            fs::read(&file)?
+        };
+        if !matches_blob(repo, &data, entry.id) {
+            return Ok(true);
+        }
+    }
+    // Anything left was deleted from the index.
+    Ok(!head.is_empty())
+}
+
+/// Whether `data` is the blob `id`, allowing for CRLF line endings in the
+/// work tree, as checkouts with core.autocrlf have.
+fn matches_blob(repo: &Repository, data: &[u8], id: ObjectId) -> bool {
+    let hash =
+        |data: &[u8]| gix::objs::compute_hash(repo.object_hash(), gix::objs::Kind::Blob, data);
+    if hash(data) == id {
+        return true;
+    }
+    data.contains_str("\r\n") && hash(&data.replace("\r\n", "\n")) == id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reopening_the_default_handle() {
+        // Tests run in the crate's own repository.
+        assert_eq!(reopen("").unwrap(), "0");
+        assert!(matches!(reopen("12345"), Err(Error::Git(_))));
+    }
+}
@@ -1,4 +1,4 @@
-use crate::error::{Error, Result};
+use crate::error::{envelope_enabled, Error, Reply, Result};
 use base64::Engine;
 use const_random::const_random;
 const XXHASH_SEED: u64 = const_random!(u64);
@@ -15,36 +15,46 @@ use std::{
 use twox_hash::XxHash64;
 
 byond_fn!(fn hash_string(algorithm, string) {
-    string_hash(algorithm, string).ok()
+    Reply::value_or_none(string_hash(algorithm, string))
 });
 
 byond_fn!(fn decode_base64(string) {
-    Some(base64::prelude::BASE64_STANDARD.decode(string).unwrap())
+    match base64::prelude::BASE64_STANDARD.decode(string) {
+        // Handed over as raw bytes, as this export always has.
+        Ok(bytes) if !envelope_enabled() => Reply::raw(bytes),
+        result => Reply::value_or_none(
+            result
+                .map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+                .map_err(Into::into),
+        ),
+    }
 });
 
 byond_fn!(fn hash_file(algorithm, string) {
-    file_hash(algorithm, string).ok()
+    Reply::value_or_none(file_hash(algorithm, string))
 });
 
 byond_fn!(fn generate_totp(hex_seed) {
-    match totp_generate(hex_seed, 0, None) {
-        Ok(value) => Some(value),
+    Reply::new(totp_generate(hex_seed, 0, None).map(Some), |r| match r {
+        Ok(value) => value,
         Err(error) => Some(format!("ERROR: {:?}", error))
-    }
+    })
 });
 
 byond_fn!(fn generate_totp_tolerance(hex_seed, tolerance) {
     let tolerance_value: i32 = match tolerance.parse() {
         Ok(value) => value,
-        Err(_) => return Some(String::from("ERROR: Tolerance not a valid integer"))
+        Err(error) => return Reply::new(Err(Error::ParseInt(error)), |_| {
+            Some(String::from("ERROR: Tolerance not a valid integer"))
+        })
     };
-    match totp_generate_tolerance(hex_seed, tolerance_value, None) {
-        Ok(value) => Some(value),
+    Reply::new(totp_generate_tolerance(hex_seed, tolerance_value, None).map(Some), |r| match r {
+        Ok(value) => value,
         Err(error) => Some(format!("ERROR: {:?}", error))
-    }
+    })
 });
 
-fn hash_algorithm<B: AsRef<[u8]>>(name: &str, bytes: B) -> Result<String> {
+pub(crate) fn hash_algorithm<B: AsRef<[u8]>>(name: &str, bytes: B) -> Result<String> {
     match name {
         "md5" => {
             let mut hasher = Md5::new();
@@ -82,7 +92,7 @@ fn string_hash(algorithm: &str, string: &str) -> Result<String> {
 
 fn file_hash(algorithm: &str, path: &str) -> Result<String> {
     let mut bytes: Vec<u8> = Vec::new();
-    let mut file = BufReader::new(File::open(path)?);
+    let mut file = BufReader::new(File::open(crate::sandbox::check(path)?)?);
     file.read_to_end(&mut bytes)?;
 
     hash_algorithm(algorithm, &bytes)
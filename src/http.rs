@@ -1,8 +1,13 @@
-use crate::{error::Result, jobs};
+use crate::{
+    error::{Reply, Result},
+    jobs, sandbox,
+};
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 use std::collections::{BTreeMap, HashMap};
 use std::io::Write;
+use std::path::PathBuf;
+use std::time::Duration;
 
 // ----------------------------------------------------------------------------
 // Interface
@@ -13,6 +18,11 @@ struct RequestOptions {
     output_filename: Option<String>,
     #[serde(default)]
     body_filename: Option<String>,
+    #[serde(default)]
+    timeout: Option<f32>,
+    #[cfg(feature = "buffer")]
+    #[serde(default)]
+    body_buffer: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -25,38 +35,37 @@ struct Response<'a> {
 // If the response can be deserialized -> success.
 // If the response can't be deserialized -> failure.
 byond_fn!(fn http_request_blocking(method, url, body, headers, options) {
-    let req = match construct_request(method, url, body, headers, options) {
-        Ok(r) => r,
-        Err(e) => return Some(e.to_string())
-    };
-
-    match submit_request(req) {
-        Ok(r) => Some(r),
-        Err(e) => Some(e.to_string())
-    }
+    let result = construct_request(method, url, body, headers, options).and_then(submit_request);
+    Reply::new(result.map(Some), legacy_reply)
 });
 
 // Returns new job-id.
 byond_fn!(fn http_request_async(method, url, body, headers, options) {
     let req = match construct_request(method, url, body, headers, options) {
         Ok(r) => r,
-        Err(e) => return Some(e.to_string())
+        Err(e) => return Reply::new(Err(e), legacy_reply)
     };
 
-    Some(jobs::start(move || {
-        match submit_request(req) {
-            Ok(r) => r,
-            Err(e) => e.to_string()
-        }
-    }))
+    let timeout = req.timeout;
+    let id = jobs::start_with_timeout(move || {
+        Reply::new(submit_request(req).map(Some), legacy_reply).into_string()
+    }, timeout);
+    Reply::new(Ok(Some(id)), legacy_reply)
 });
 
 // If the response can be deserialized -> success.
 // If the response can't be deserialized -> failure or WIP.
 byond_fn!(fn http_check_request(id) {
-    Some(jobs::check(id))
+    Reply::raw(jobs::check(id))
 });
 
+fn legacy_reply(result: Result<Option<String>>) -> Option<String> {
+    Some(match result {
+        Ok(value) => value.unwrap_or_default(),
+        Err(e) => e.to_string(),
+    })
+}
+
 // ----------------------------------------------------------------------------
 // Shared HTTP client state
 
@@ -82,7 +91,8 @@ pub static HTTP_CLIENT: Lazy<reqwest::blocking::Client> = Lazy::new(setup_http_c
 
 struct RequestPrep {
     req: reqwest::blocking::RequestBuilder,
-    output_filename: Option<String>,
+    output_filename: Option<PathBuf>,
+    timeout: Option<Duration>,
 }
 
 fn construct_request(
@@ -113,17 +123,32 @@ fn construct_request(
     }
 
     let mut output_filename = None;
+    let mut timeout = None;
     if !options.is_empty() {
         let options: RequestOptions = serde_json::from_str(options)?;
-        output_filename = options.output_filename;
+        output_filename = options.output_filename.map(sandbox::check).transpose()?;
         if let Some(fname) = options.body_filename {
-            req = req.body(std::fs::File::open(fname)?);
+            req = req.body(std::fs::File::open(sandbox::check(fname)?)?);
+        }
+        #[cfg(feature = "buffer")]
+        if let Some(handle) = options.body_buffer {
+            req = req.body(crate::buffer::get(&handle)?.to_vec());
+        }
+        // Also bound the request itself, so a hung server doesn't hold on to
+        // a job worker after the job is reported as timed out.
+        timeout = options
+            .timeout
+            .map(Duration::try_from_secs_f32)
+            .transpose()?;
+        if let Some(timeout) = timeout {
+            req = req.timeout(timeout);
         }
     }
 
     Ok(RequestPrep {
         req,
         output_filename,
+        timeout,
     })
 }
 
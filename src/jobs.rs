@@ -1,22 +1,138 @@
 //! Job system
-use flume::Receiver;
+use crate::error::{Error, Reply, Result};
+use flume::{Receiver, RecvTimeoutError, Sender};
+use once_cell::sync::Lazy;
 use std::{
     cell::RefCell,
     collections::hash_map::{Entry, HashMap},
+    panic::{self, AssertUnwindSafe},
+    sync::atomic::{AtomicUsize, Ordering},
     thread,
+    time::{Duration, Instant},
 };
 
 struct Job {
     rx: Receiver<Output>,
-    handle: thread::JoinHandle<()>,
+    deadline: Option<Instant>,
+}
+
+impl Job {
+    fn timed_out(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
 }
 
 type Output = String;
 type JobId = String;
+type Task = Box<dyn FnOnce() + Send>;
 
 const NO_RESULTS_YET: &str = "NO RESULTS YET";
 const NO_SUCH_JOB: &str = "NO SUCH JOB";
 const JOB_PANICKED: &str = "JOB PANICKED";
+const JOB_PENDING: &str = "JOB PENDING";
+const JOB_DONE: &str = "JOB DONE";
+const JOB_TIMED_OUT: &str = "JOB TIMED OUT";
+
+const DEFAULT_POOL_SIZE: usize = 16;
+// How often idle workers wake up to see if the pool has been shrunk.
+const IDLE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// ----------------------------------------------------------------------------
+// Worker pool
+
+struct Pool {
+    tx: Sender<Task>,
+    rx: Receiver<Task>,
+    size: AtomicUsize,
+    workers: AtomicUsize,
+}
+
+static POOL: Lazy<Pool> = Lazy::new(|| {
+    let (tx, rx) = flume::unbounded();
+    Pool {
+        tx,
+        rx,
+        size: AtomicUsize::new(DEFAULT_POOL_SIZE),
+        workers: AtomicUsize::new(0),
+    }
+});
+
+impl Pool {
+    fn submit(&'static self, task: Task) {
+        let _ = self.tx.send(task);
+        self.spawn_workers();
+    }
+
+    fn resize(&'static self, size: usize) {
+        self.size.store(size, Ordering::SeqCst);
+        // Surplus workers retire on their own once they're idle.
+        self.spawn_workers();
+    }
+
+    fn spawn_workers(&'static self) {
+        loop {
+            let workers = self.workers.load(Ordering::SeqCst);
+            if workers >= self.size.load(Ordering::SeqCst) {
+                return;
+            }
+            if self
+                .workers
+                .compare_exchange(workers, workers + 1, Ordering::SeqCst, Ordering::SeqCst)
+                .is_err()
+            {
+                continue;
+            }
+            let spawned = thread::Builder::new()
+                .name("rustg-job-worker".to_owned())
+                .spawn(move || self.work());
+            if spawned.is_err() {
+                self.workers.fetch_sub(1, Ordering::SeqCst);
+                return;
+            }
+        }
+    }
+
+    fn work(&self) {
+        loop {
+            if self.try_retire() {
+                return;
+            }
+            match self.rx.recv_timeout(IDLE_POLL_INTERVAL) {
+                // A panicking job drops its sender while unwinding, which is
+                // how `check` knows to report it. The worker itself survives.
+                Ok(task) => {
+                    let _ = panic::catch_unwind(AssertUnwindSafe(task));
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.workers.fetch_sub(1, Ordering::SeqCst);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn try_retire(&self) -> bool {
+        let size = self.size.load(Ordering::SeqCst);
+        self.workers
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |workers| {
+                (workers > size).then(|| workers - 1)
+            })
+            .is_ok()
+    }
+
+    fn stats(&self) -> serde_json::Value {
+        serde_json::json!({
+            "size": self.size.load(Ordering::SeqCst),
+            "workers": self.workers.load(Ordering::SeqCst),
+            "queued": self.rx.len(),
+        })
+    }
+}
+
+// ----------------------------------------------------------------------------
+// Job bookkeeping
 
 #[derive(Default)]
 struct Jobs {
@@ -25,14 +141,22 @@ struct Jobs {
 }
 
 impl Jobs {
-    fn start<F: FnOnce() -> Output + Send + 'static>(&mut self, f: F) -> JobId {
+    fn start<F: FnOnce() -> Output + Send + 'static>(
+        &mut self,
+        f: F,
+        timeout: Option<Duration>,
+    ) -> JobId {
         let (tx, rx) = flume::unbounded();
-        let handle = thread::spawn(move || {
-            let _ = tx.send(f());
-        });
+        POOL.submit(Box::new(move || {
+            // Skip jobs which were cancelled while still queued.
+            if !tx.is_disconnected() {
+                let _ = tx.send(f());
+            }
+        }));
         let id = self.next_job.to_string();
         self.next_job += 1;
-        self.map.insert(id.clone(), Job { rx, handle });
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        self.map.insert(id.clone(), Job { rx, deadline });
         id
     }
 
@@ -44,11 +168,52 @@ impl Jobs {
         let result = match entry.get().rx.try_recv() {
             Ok(result) => result,
             Err(flume::TryRecvError::Disconnected) => JOB_PANICKED.to_owned(),
+            Err(flume::TryRecvError::Empty) if entry.get().timed_out() => {
+                // Dropping the receiver also stops the job if it's still queued.
+                entry.remove();
+                return JOB_TIMED_OUT.to_owned();
+            }
             Err(flume::TryRecvError::Empty) => return NO_RESULTS_YET.to_owned(),
         };
-        let _ = entry.remove().handle.join();
+        entry.remove();
         result
     }
+
+    /// Checks every job in `ids`, removing the finished ones.
+    fn check_many(&mut self, ids: &[String]) -> serde_json::Map<String, serde_json::Value> {
+        ids.iter()
+            .map(|id| (id.clone(), self.check(id).into()))
+            .collect()
+    }
+
+    /// Reports the state of a job without consuming its result.
+    fn status(&self, id: &str) -> &'static str {
+        let job = match self.map.get(id) {
+            Some(job) => job,
+            None => return NO_SUCH_JOB,
+        };
+        if !job.rx.is_empty() {
+            JOB_DONE
+        } else if job.rx.is_disconnected() {
+            JOB_PANICKED
+        } else if job.timed_out() {
+            JOB_TIMED_OUT
+        } else {
+            JOB_PENDING
+        }
+    }
+
+    /// Forgets a job. Queued jobs are skipped, but a running job can't be
+    /// interrupted, so whatever it eventually returns is discarded.
+    fn cancel(&mut self, id: &str) -> bool {
+        self.map.remove(id).is_some()
+    }
+
+    fn list(&self) -> Vec<&str> {
+        let mut ids: Vec<&str> = self.map.keys().map(String::as_str).collect();
+        ids.sort_by_key(|id| id.parse::<usize>().unwrap_or(usize::MAX));
+        ids
+    }
 }
 
 thread_local! {
@@ -56,9 +221,126 @@ thread_local! {
 }
 
 pub fn start<F: FnOnce() -> Output + Send + 'static>(f: F) -> JobId {
-    JOBS.with(|jobs| jobs.borrow_mut().start(f))
+    start_with_timeout(f, None)
+}
+
+/// Starts a job which `check` reports as timed out, and forgets, once
+/// `timeout` has passed without a result.
+pub fn start_with_timeout<F: FnOnce() -> Output + Send + 'static>(
+    f: F,
+    timeout: Option<Duration>,
+) -> JobId {
+    JOBS.with(|jobs| jobs.borrow_mut().start(f, timeout))
 }
 
 pub fn check(id: &str) -> String {
     JOBS.with(|jobs| jobs.borrow_mut().check(id))
 }
+
+byond_fn!(fn jobs_check_many(ids) {
+    Reply::new(check_many(ids).map(Some), |r| r.unwrap_or_else(|e| Some(e.to_string())))
+});
+
+fn check_many(ids: &str) -> Result<String> {
+    // DM tends to hand over job ids as numbers, so accept those as well.
+    let ids: Vec<String> = serde_json::from_str::<Vec<serde_json::Value>>(ids)?
+        .into_iter()
+        .map(|id| match id {
+            serde_json::Value::String(id) => id,
+            other => other.to_string(),
+        })
+        .collect();
+    let results = JOBS.with(|jobs| jobs.borrow_mut().check_many(&ids));
+    Ok(serde_json::Value::Object(results).to_string())
+}
+
+byond_fn!(fn job_status(id) {
+    Some(JOBS.with(|jobs| jobs.borrow().status(id)))
+});
+
+byond_fn!(fn job_cancel(id) {
+    Some(JOBS.with(|jobs| jobs.borrow_mut().cancel(id)).to_string())
+});
+
+byond_fn!(
+    fn job_list() {
+        JOBS.with(|jobs| serde_json::to_string(&jobs.borrow().list()).ok())
+    }
+);
+
+byond_fn!(fn job_pool_set_size(size) {
+    Reply::error_or_none(set_pool_size(size))
+});
+
+fn set_pool_size(size: &str) -> Result<()> {
+    match size.parse::<usize>()? {
+        0 => Err(Error::InvalidPoolSize),
+        size => {
+            POOL.resize(size);
+            Ok(())
+        }
+    }
+}
+
+byond_fn!(
+    fn job_pool_stats() {
+        Some(POOL.stats().to_string())
+    }
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wait_for(jobs: &Jobs, id: &str) {
+        while jobs.status(id) == JOB_PENDING {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn check_many_removes_finished_jobs() {
+        let mut jobs = Jobs::default();
+        let done = jobs.start(|| "done".to_owned(), None);
+        let (release, blocked) = flume::bounded::<()>(0);
+        let pending = jobs.start(
+            move || {
+                let _ = blocked.recv();
+                "late".to_owned()
+            },
+            None,
+        );
+        wait_for(&jobs, &done);
+
+        let results = jobs.check_many(&[done.clone(), pending.clone(), "nope".to_owned()]);
+        assert_eq!(results[&done], "done");
+        assert_eq!(results[&pending], NO_RESULTS_YET);
+        assert_eq!(results["nope"], NO_SUCH_JOB);
+        assert_eq!(jobs.list(), vec![pending.as_str()]);
+        drop(release);
+    }
+
+    #[test]
+    fn timed_out_jobs_are_forgotten() {
+        let mut jobs = Jobs::default();
+        let id = jobs.start(
+            || {
+                thread::sleep(Duration::from_millis(200));
+                "late".to_owned()
+            },
+            Some(Duration::ZERO),
+        );
+        assert_eq!(jobs.status(&id), JOB_TIMED_OUT);
+        assert_eq!(jobs.check(&id), JOB_TIMED_OUT);
+        assert_eq!(jobs.check(&id), NO_SUCH_JOB);
+    }
+
+    #[test]
+    fn panicking_jobs_are_reported() {
+        let mut jobs = Jobs::default();
+        let id = jobs.start(|| panic!("oh no"), None);
+        wait_for(&jobs, &id);
+        assert_eq!(jobs.status(&id), JOB_PANICKED);
+        assert_eq!(jobs.check(&id), JOB_PANICKED);
+    }
+}
@@ -4,20 +4,29 @@
 mod byond;
 #[allow(dead_code)]
 mod error;
+mod sandbox;
+#[cfg(test)]
+mod test_util;
 
 #[cfg(feature = "jobs")]
 mod jobs;
 
 #[cfg(feature = "acreplace")]
 pub mod acreplace;
+#[cfg(feature = "buffer")]
+pub mod buffer;
 #[cfg(feature = "cellularnoise")]
 pub mod cellularnoise;
+#[cfg(feature = "cron")]
+pub mod cron;
 #[cfg(feature = "dbpnoise")]
 pub mod dbpnoise;
 #[cfg(feature = "dmi")]
 pub mod dmi;
 #[cfg(feature = "file")]
 pub mod file;
+#[cfg(feature = "filewatch")]
+pub mod filewatch;
 #[cfg(feature = "git")]
 pub mod git;
 #[cfg(feature = "hash")]
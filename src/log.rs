@@ -1,63 +1,670 @@
-use crate::error::Result;
-use chrono::Utc;
+use crate::{
+    byond::deserialize_byond_bool,
+    error::{Error, Reply, Result},
+    sandbox,
+    time::{self, Timezone},
+};
+use chrono::{DateTime, NaiveDate, SecondsFormat, Utc};
+use flate2::{write::GzEncoder, Compression};
+use serde::Deserialize;
 use std::{
     cell::RefCell,
-    collections::hash_map::{Entry, HashMap},
-    ffi::OsString,
+    collections::HashMap,
     fs,
     fs::{File, OpenOptions},
-    io::Write,
-    path::Path,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    thread::{self, JoinHandle},
 };
 
+type JsonObject = serde_json::Map<String, serde_json::Value>;
+
 thread_local! {
-    static FILE_MAP: RefCell<HashMap<OsString, File>> = RefCell::new(HashMap::new());
+    static LOGS: RefCell<Mode> = RefCell::new(Mode::Sync(Logs::default()));
+    // Fields added to every line written with `log_write_json`, per path.
+    static JSON_FIELDS: RefCell<HashMap<PathBuf, JsonObject>> = RefCell::new(HashMap::new());
+    // How `log_write` timestamps lines, per path, falling back to the global setting.
+    static TIMESTAMPS: RefCell<Timestamps> = RefCell::new(Timestamps::default());
 }
 
 byond_fn!(fn log_write(path, data, ...rest) {
-    FILE_MAP.with(|cell| -> Result<()> {
-        // open file
-        let mut map = cell.borrow_mut();
-        let path = Path::new(path as &str);
-        let file = match map.entry(path.into()) {
-            Entry::Occupied(elem) => elem.into_mut(),
-            Entry::Vacant(elem) => elem.insert(open(path)?),
-        };
-
+    let result = LOGS.with(|cell| -> Result<()> {
+        let path = sandbox::check(path)?;
+        let mut line = Vec::new();
         if rest.first().map(|x| &**x) == Some("false") {
             // Write the data to the file with no accoutrements.
-            write!(file, "{data}")?;
+            write!(line, "{data}")?;
         } else {
             // write first line, timestamped
             let mut iter = data.split('\n');
-            if let Some(line) = iter.next() {
-                writeln!(file, "[{}] {}", Utc::now().format("%F %T%.3f"), line)?;
+            if let Some(first) = iter.next() {
+                TIMESTAMPS.with(|cell| cell.borrow().get(&path).write(&mut line))?;
+                writeln!(line, "{first}")?;
             }
 
             // write remaining lines
-            for line in iter {
-                writeln!(file, " - {line}")?;
+            for continuation in iter {
+                writeln!(line, " - {continuation}")?;
             }
         }
 
+        cell.borrow_mut().write(&path, &line)
+    });
+    Reply::error_or_none(result)
+});
+
+// Writes a JSON object as a single line, with a timestamp and the path's fields added.
+byond_fn!(fn log_write_json(path, data) {
+    let result = sandbox::check(path).and_then(|path| {
+        let line = json_line(&path, serde_json::from_str(data)?)?;
+        LOGS.with(|cell| cell.borrow_mut().write(&path, &line))
+    });
+    Reply::error_or_none(result)
+});
+
+// Sets the fields, as a JSON object, which are added to every line log_write_json writes to a path.
+byond_fn!(fn log_set_json_fields(path, fields) {
+    let result = sandbox::check(path).and_then(|path| {
+        let fields: JsonObject = if fields.is_empty() {
+            JsonObject::new()
+        } else {
+            serde_json::from_str(fields)?
+        };
+        JSON_FIELDS.with(|cell| {
+            let mut map = cell.borrow_mut();
+            if fields.is_empty() {
+                map.remove(&path);
+            } else {
+                map.insert(path, fields);
+            }
+        });
+        Ok(())
+    });
+    Reply::error_or_none(result)
+});
+
+// Sets how log_write timestamps a path, as JSON, or every path without its own
+// setting if the path is empty. Empty options go back to the default.
+byond_fn!(fn log_set_timestamp(path, options) {
+    let result = (|| -> Result<()> {
+        let timestamp = if options.is_empty() {
+            None
+        } else {
+            Some(serde_json::from_str::<Timestamp>(options)?)
+        };
+        let path = if path.is_empty() {
+            None
+        } else {
+            Some(sandbox::check(path)?)
+        };
+        TIMESTAMPS.with(|cell| cell.borrow_mut().set(path, timestamp));
         Ok(())
-    }).err()
+    })();
+    Reply::error_or_none(result)
+});
+
+// Sets how a log is rotated, as JSON. Empty options stop rotating it.
+byond_fn!(fn log_set_rotation(path, options) {
+    let result = sandbox::check(path).and_then(|path| {
+        let rotation = if options.is_empty() {
+            None
+        } else {
+            Some(serde_json::from_str::<Rotation>(options)?)
+        };
+        LOGS.with(|cell| cell.borrow_mut().set_rotation(path, rotation))
+    });
+    Reply::error_or_none(result)
 });
 
+// Switches between writing on the calling thread and handing lines to a
+// background writer. Turning it off waits for the writer to catch up.
+byond_fn!(fn log_set_async(enabled) {
+    let enabled = enabled == "1" || enabled == "true";
+    LOGS.with(|cell| cell.borrow_mut().set_async(enabled));
+    Some("")
+});
+
+// Waits for queued lines to be written, returning the first error since the last flush.
+byond_fn!(
+    fn log_flush() {
+        Reply::error_or_none(LOGS.with(|cell| cell.borrow_mut().flush()))
+    }
+);
+
 byond_fn!(
     fn log_close_all() {
-        FILE_MAP.with(|cell| {
-            let mut map = cell.borrow_mut();
-            map.clear();
-        });
-        Some("")
+        Reply::error_or_none(LOGS.with(|cell| cell.borrow_mut().close_all()))
     }
 );
 
-fn open(path: &Path) -> Result<File> {
-    if let Some(parent) = path.parent() {
-        fs::create_dir_all(parent)?;
+/// Builds a line for `log_write_json`. The object's own fields win over the
+/// path's fields, which win over the timestamp.
+fn json_line(path: &Path, object: JsonObject) -> Result<Vec<u8>> {
+    let mut line = JsonObject::new();
+    line.insert(
+        "timestamp".to_owned(),
+        Utc::now()
+            .to_rfc3339_opts(SecondsFormat::Millis, true)
+            .into(),
+    );
+    JSON_FIELDS.with(|cell| {
+        if let Some(fields) = cell.borrow().get(path) {
+            line.extend(fields.clone());
+        }
+    });
+    line.extend(object);
+    let mut line = serde_json::to_vec(&line)?;
+    line.push(b'\n');
+    Ok(line)
+}
+
+/// Where writes go: straight to the files, or through the writer thread,
+/// which owns the `Logs` for as long as it runs.
+enum Mode {
+    Sync(Logs),
+    Async(Writer),
+}
+
+struct Writer {
+    tx: flume::Sender<Command>,
+    thread: JoinHandle<Logs>,
+}
+
+enum Command {
+    Write(PathBuf, Vec<u8>),
+    SetRotation(PathBuf, Option<Rotation>),
+    /// Replies once everything before it is written, with the first error since the last reply.
+    Flush(flume::Sender<Option<Error>>),
+    /// Like `Flush`, then closes every file.
+    CloseAll(flume::Sender<Option<Error>>),
+}
+
+impl Mode {
+    fn write(&mut self, path: &Path, data: &[u8]) -> Result<()> {
+        match self {
+            Mode::Sync(logs) => logs.write(path, data),
+            Mode::Async(writer) => writer.send(Command::Write(path.to_owned(), data.to_owned())),
+        }
+    }
+
+    fn set_rotation(&mut self, path: PathBuf, rotation: Option<Rotation>) -> Result<()> {
+        match self {
+            Mode::Sync(logs) => {
+                logs.set_rotation(path, rotation);
+                Ok(())
+            }
+            Mode::Async(writer) => writer.send(Command::SetRotation(path, rotation)),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            Mode::Sync(_) => Ok(()),
+            Mode::Async(writer) => writer.request(Command::Flush),
+        }
+    }
+
+    fn close_all(&mut self) -> Result<()> {
+        match self {
+            Mode::Sync(logs) => logs.close_all(),
+            Mode::Async(writer) => writer.request(Command::CloseAll),
+        }
+    }
+
+    fn set_async(&mut self, enabled: bool) {
+        let current = std::mem::replace(self, Mode::Sync(Logs::default()));
+        *self = match (current, enabled) {
+            (Mode::Sync(logs), true) => {
+                let (tx, rx) = flume::unbounded();
+                // The files are only handed over once the thread exists, so
+                // they're still ours if it can't be spawned.
+                let (handoff, handed) = flume::bounded(1);
+                let thread = thread::Builder::new()
+                    .name("rustg-log-writer".to_owned())
+                    .spawn(move || match handed.recv() {
+                        Ok(logs) => run_writer(logs, rx),
+                        Err(_) => Logs::default(),
+                    });
+                match thread {
+                    Ok(thread) => {
+                        let _ = handoff.send(logs);
+                        Mode::Async(Writer { tx, thread })
+                    }
+                    // Keep writing synchronously rather than not at all.
+                    Err(_) => Mode::Sync(logs),
+                }
+            }
+            (Mode::Async(Writer { tx, thread }), false) => {
+                // The writer finishes the queue and hands the files back once it's disconnected.
+                drop(tx);
+                Mode::Sync(thread.join().unwrap_or_default())
+            }
+            (current, _) => current,
+        };
+    }
+}
+
+impl Writer {
+    fn send(&self, command: Command) -> Result<()> {
+        self.tx.send(command).map_err(|_| writer_gone())
+    }
+
+    fn request(&self, command: fn(flume::Sender<Option<Error>>) -> Command) -> Result<()> {
+        let (ack, done) = flume::bounded(1);
+        self.send(command(ack))?;
+        match done.recv() {
+            Ok(Some(error)) => Err(error),
+            Ok(None) => Ok(()),
+            Err(_) => Err(writer_gone()),
+        }
+    }
+}
+
+fn writer_gone() -> Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "log writer thread has stopped").into()
+}
+
+/// The writer thread. Whatever is queued up by the time it gets around to it
+/// is written with one write per file.
+fn run_writer(mut logs: Logs, rx: flume::Receiver<Command>) -> Logs {
+    let mut error = None;
+    let mut batch: Vec<(PathBuf, Vec<u8>)> = Vec::new();
+    while let Ok(first) = rx.recv() {
+        for command in std::iter::once(first).chain(rx.try_iter()) {
+            match command {
+                Command::Write(path, data) => {
+                    match batch.iter_mut().find(|(queued, _)| *queued == path) {
+                        Some((_, queued)) => queued.extend_from_slice(&data),
+                        None => batch.push((path, data)),
+                    }
+                }
+                Command::SetRotation(path, rotation) => {
+                    write_batch(&mut logs, &mut batch, &mut error);
+                    logs.set_rotation(path, rotation);
+                }
+                Command::Flush(ack) => {
+                    write_batch(&mut logs, &mut batch, &mut error);
+                    let _ = ack.send(error.take());
+                }
+                Command::CloseAll(ack) => {
+                    write_batch(&mut logs, &mut batch, &mut error);
+                    if let Err(e) = logs.close_all() {
+                        error.get_or_insert(e);
+                    }
+                    let _ = ack.send(error.take());
+                }
+            }
+        }
+        write_batch(&mut logs, &mut batch, &mut error);
+    }
+    logs
+}
+
+fn write_batch(logs: &mut Logs, batch: &mut Vec<(PathBuf, Vec<u8>)>, error: &mut Option<Error>) {
+    for (path, data) in batch.drain(..) {
+        if let Err(e) = logs.write(&path, &data) {
+            error.get_or_insert(e);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Logs {
+    files: HashMap<PathBuf, LogFile>,
+    rotations: HashMap<PathBuf, Rotation>,
+}
+
+impl Logs {
+    fn write(&mut self, path: &Path, data: &[u8]) -> Result<()> {
+        if !self.files.contains_key(path) {
+            self.files.insert(path.to_owned(), LogFile::open(path)?);
+        }
+        if let (Some(rotation), Some(file)) = (self.rotations.get_mut(path), self.files.get(path)) {
+            if rotation.is_due(file, data.len() as u64) {
+                // Closed first, since Windows won't rename an open file.
+                self.files.remove(path);
+                rotation.rotate(path)?;
+                self.files.insert(path.to_owned(), LogFile::open(path)?);
+            }
+        }
+        if let Some(file) = self.files.get_mut(path) {
+            file.file.write_all(data)?;
+            file.size += data.len() as u64;
+        }
+        Ok(())
     }
 
-    Ok(OpenOptions::new().append(true).create(true).open(path)?)
+    /// Closes every file and waits for rotated logs to finish compressing.
+    fn close_all(&mut self) -> Result<()> {
+        self.files.clear();
+        let mut result = Ok(());
+        for rotation in self.rotations.values_mut() {
+            result = result.and(rotation.wait());
+        }
+        result
+    }
+
+    fn set_rotation(&mut self, path: PathBuf, rotation: Option<Rotation>) {
+        match rotation {
+            Some(rotation) => self.rotations.insert(path, rotation),
+            None => self.rotations.remove(&path),
+        };
+    }
+}
+
+struct LogFile {
+    file: File,
+    size: u64,
+    /// The day this log began, as far as daily rotation is concerned.
+    day: NaiveDate,
+}
+
+impl LogFile {
+    fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+        let metadata = file.metadata()?;
+        // A log left over from yesterday gets rotated on the first write today.
+        let day = metadata
+            .modified()
+            .map_or_else(|_| Utc::now(), DateTime::<Utc>::from)
+            .date_naive();
+        Ok(Self {
+            file,
+            size: metadata.len(),
+            day,
+        })
+    }
+}
+
+/// When and how to move a log out of the way. Rotated logs are renamed to
+/// `<path>.1`, `<path>.2` and so on, newest first, with `.gz` on the end if
+/// they're compressed.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Rotation {
+    /// Rotates before a write would take the log past this many bytes.
+    max_size: Option<u64>,
+    /// Rotates on the first write of each day, going by UTC like the timestamps.
+    #[serde(deserialize_with = "deserialize_byond_bool")]
+    daily: bool,
+    /// How many rotated logs to keep. Unlimited if unset.
+    max_files: Option<usize>,
+    /// Gzips rotated logs.
+    #[serde(deserialize_with = "deserialize_byond_bool")]
+    compress: bool,
+    /// The thread gzipping the last rotated log, if it's still going.
+    #[serde(skip)]
+    compressing: Option<JoinHandle<Result<()>>>,
+}
+
+impl Rotation {
+    fn is_due(&self, file: &LogFile, incoming: u64) -> bool {
+        let too_big = self
+            .max_size
+            .is_some_and(|max_size| file.size > 0 && file.size + incoming > max_size);
+        too_big || (self.daily && file.day != Utc::now().date_naive())
+    }
+
+    fn rotated_name(&self, path: &Path, n: usize) -> PathBuf {
+        let mut name = path.as_os_str().to_owned();
+        name.push(format!(".{n}"));
+        if self.compress {
+            name.push(".gz");
+        }
+        name.into()
+    }
+
+    fn rotate(&mut self, path: &Path) -> Result<()> {
+        // The last rotated log has to be in place before everything moves up one.
+        self.wait()?;
+        if self.max_files == Some(0) {
+            return Ok(fs::remove_file(path)?);
+        }
+
+        let mut end = 1;
+        while self.rotated_name(path, end).exists() {
+            end += 1;
+        }
+        for n in (1..end).rev() {
+            if self.max_files.is_some_and(|max_files| n >= max_files) {
+                fs::remove_file(self.rotated_name(path, n))?;
+            } else {
+                fs::rename(self.rotated_name(path, n), self.rotated_name(path, n + 1))?;
+            }
+        }
+
+        let rotated = self.rotated_name(path, 1);
+        if !self.compress {
+            return Ok(fs::rename(path, rotated)?);
+        }
+        // Only the rename happens on the caller's time. The log is compressed
+        // from `<path>.1`, which is removed once `<path>.1.gz` is written.
+        let mut uncompressed = path.as_os_str().to_owned();
+        uncompressed.push(".1");
+        let uncompressed = PathBuf::from(uncompressed);
+        fs::rename(path, &uncompressed)?;
+        let (from, to) = (uncompressed.clone(), rotated.clone());
+        match thread::Builder::new()
+            .name("rustg-log-compress".to_owned())
+            .spawn(move || compress(&from, &to))
+        {
+            Ok(thread) => self.compressing = Some(thread),
+            Err(_) => compress(&uncompressed, &rotated)?,
+        }
+        Ok(())
+    }
+
+    /// Waits for the last rotated log to be compressed, returning any error doing so.
+    fn wait(&mut self) -> Result<()> {
+        match self.compressing.take() {
+            Some(thread) => thread.join().unwrap_or_else(|_| {
+                Err(io::Error::new(io::ErrorKind::Other, "log compression panicked").into())
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+fn compress(from: &Path, to: &Path) -> Result<()> {
+    let mut encoder = GzEncoder::new(File::create(to)?, Compression::default());
+    io::copy(&mut File::open(from)?, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+    Ok(fs::remove_file(from)?)
+}
+
+#[derive(Default)]
+struct Timestamps {
+    global: Timestamp,
+    paths: HashMap<PathBuf, Timestamp>,
+}
+
+impl Timestamps {
+    fn get(&self, path: &Path) -> &Timestamp {
+        self.paths.get(path).unwrap_or(&self.global)
+    }
+
+    fn set(&mut self, path: Option<PathBuf>, timestamp: Option<Timestamp>) {
+        match (path, timestamp) {
+            (None, timestamp) => self.global = timestamp.unwrap_or_default(),
+            (Some(path), Some(timestamp)) => {
+                self.paths.insert(path, timestamp);
+            }
+            (Some(path), None) => {
+                self.paths.remove(&path);
+            }
+        }
+    }
+}
+
+/// The `[timestamp]` log_write puts before the first line of each write.
+#[derive(Deserialize)]
+#[serde(default)]
+struct Timestamp {
+    /// A strftime format.
+    #[serde(deserialize_with = "deserialize_strftime")]
+    format: String,
+    #[serde(deserialize_with = "deserialize_timezone")]
+    timezone: Timezone,
+    /// The ID of a `time` instant. If set, the seconds since it started are
+    /// added in a second pair of brackets.
+    elapsed: Option<String>,
+}
+
+impl Default for Timestamp {
+    fn default() -> Self {
+        Self {
+            format: "%F %T%.3f".to_owned(),
+            timezone: Timezone::Utc,
+            elapsed: None,
+        }
+    }
+}
+
+impl Timestamp {
+    fn write(&self, line: &mut Vec<u8>) -> Result<()> {
+        write!(
+            line,
+            "[{}] ",
+            self.timezone.format(Utc::now(), &self.format)
+        )?;
+        if let Some(instant_id) = &self.elapsed {
+            let elapsed = time::elapsed(instant_id)?;
+            write!(line, "[{:.3}] ", elapsed.as_secs_f64())?;
+        }
+        Ok(())
+    }
+}
+
+fn deserialize_strftime<'de, D>(deserializer: D) -> std::result::Result<String, D::Error>
+where
+    D: serde::de::Deserializer<'de>,
+{
+    let format = String::deserialize(deserializer)?;
+    time::check_format(&format).map_err(serde::de::Error::custom)?;
+    Ok(format)
+}
+
+fn deserialize_timezone<'de, D>(deserializer: D) -> std::result::Result<Timezone, D::Error>
+where
+    D: serde::de::Deserializer<'de>,
+{
+    let timezone = String::deserialize(deserializer)?;
+    timezone.parse().map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn logs_rotate_by_size() {
+        let dir = TempDir::new("log-rotate");
+        let path = dir.join("game.log");
+        let mut logs = Logs::default();
+        let rotation = Rotation {
+            max_size: Some(10),
+            max_files: Some(2),
+            ..Default::default()
+        };
+        logs.set_rotation(path.clone(), Some(rotation));
+
+        for line in ["one\n", "two\n", "three\n", "four\n", "five\n"] {
+            logs.write(&path, line.as_bytes()).unwrap();
+        }
+        logs.files.clear();
+        let read = |n: &str| fs::read_to_string(dir.join(format!("game.log{n}"))).unwrap();
+        assert_eq!(read(""), "four\nfive\n");
+        assert_eq!(read(".1"), "three\n");
+        assert_eq!(read(".2"), "one\ntwo\n");
+        assert!(!dir.join("game.log.3").exists());
+    }
+
+    #[test]
+    fn rotated_logs_are_compressed_in_the_background() {
+        let dir = TempDir::new("log-compress");
+        let path = dir.join("game.log");
+        let mut logs = Logs::default();
+        let rotation = Rotation {
+            max_size: Some(4),
+            compress: true,
+            ..Default::default()
+        };
+        logs.set_rotation(path.clone(), Some(rotation));
+
+        for line in ["one\n", "two\n", "three\n"] {
+            logs.write(&path, line.as_bytes()).unwrap();
+        }
+        logs.close_all().unwrap();
+        let unzip = |n: usize| {
+            let file = File::open(dir.join(format!("game.log.{n}.gz"))).unwrap();
+            io::read_to_string(flate2::read::GzDecoder::new(file)).unwrap()
+        };
+        assert_eq!(fs::read_to_string(&path).unwrap(), "three\n");
+        assert_eq!(unzip(1), "two\n");
+        assert_eq!(unzip(2), "one\n");
+        assert!(!dir.join("game.log.1").exists());
+    }
+
+    #[test]
+    fn async_writes_are_flushed_in_order() {
+        let dir = TempDir::new("log-async");
+        let (a, b) = (dir.join("a.log"), dir.join("b.log"));
+        let mut mode = Mode::Sync(Logs::default());
+        mode.set_async(true);
+        for n in 0..100 {
+            mode.write(
+                if n % 2 == 0 { &a } else { &b },
+                format!("{n}\n").as_bytes(),
+            )
+            .unwrap();
+        }
+        mode.close_all().unwrap();
+        let evens: String = (0..100).step_by(2).map(|n| format!("{n}\n")).collect();
+        assert_eq!(fs::read_to_string(&a).unwrap(), evens);
+        mode.set_async(false);
+        assert!(matches!(mode, Mode::Sync(_)));
+    }
+
+    #[test]
+    fn json_lines_merge_fields() {
+        let path = Path::new("json-fields.log");
+        let fields = serde_json::json!({ "round_id": 7, "server": "main" });
+        JSON_FIELDS.with(|cell| {
+            let fields = fields.as_object().unwrap().clone();
+            cell.borrow_mut().insert(path.to_owned(), fields)
+        });
+        let object = serde_json::json!({ "server": "override", "message": "hi" });
+        let line = json_line(path, object.as_object().unwrap().clone()).unwrap();
+        let line: serde_json::Value = serde_json::from_slice(&line).unwrap();
+        assert_eq!(line["round_id"], 7);
+        assert_eq!(line["server"], "override");
+        assert_eq!(line["message"], "hi");
+        assert!(line["timestamp"].as_str().unwrap().ends_with('Z'));
+    }
+
+    #[test]
+    fn timestamps_follow_their_options() {
+        let options = r#"{"format": "%H:%M %z", "timezone": "+10:00", "elapsed": "log-test"}"#;
+        let timestamp: Timestamp = serde_json::from_str(options).unwrap();
+        let mut line = Vec::new();
+        assert!(matches!(
+            timestamp.write(&mut line),
+            Err(Error::UnknownTimer)
+        ));
+        time::instant("log-test");
+        line.clear();
+        timestamp.write(&mut line).unwrap();
+        let line = String::from_utf8(line).unwrap();
+        assert!(line.contains(" +1000] ["), "{line}");
+
+        assert!(serde_json::from_str::<Timestamp>(r#"{"format": "%Q"}"#).is_err());
+        assert!(serde_json::from_str::<Timestamp>(r#"{"timezone": "mars"}"#).is_err());
+        let local: Timestamp = serde_json::from_str(r#"{"timezone": "Local"}"#).unwrap();
+        assert_eq!(local.format, "%F %T%.3f");
+    }
 }
@@ -4,35 +4,39 @@ use std::{
     collections::hash_map::{Entry, HashMap},
 };
 
-use crate::error::Result;
+use crate::error::{Reply, Result};
 
 thread_local! {
-    static GENERATORS: RefCell<HashMap<String,  Perlin>> = RefCell::new(HashMap::new());
+    static GENERATORS: RefCell<HashMap<u32,  Perlin>> = RefCell::new(HashMap::new());
 }
 
 byond_fn!(fn noise_get_at_coordinates(seed, x, y) {
-    get_at_coordinates(seed, x, y).ok()
+    Reply::value_or_none(get_at_coordinates(seed, x, y))
+});
+
+// Same as above, for call_ext's byondapi convention on 515+.
+byond_fn_native!(fn noise_get_at_coordinates_native(seed, x, y) {
+    Some(sample(seed? as u32, x?.into(), y?.into()) as f32)
 });
 
-//note that this will be 0 at integer x & y, scaling is left up to the caller
 fn get_at_coordinates(seed_as_str: &str, x_as_str: &str, y_as_str: &str) -> Result<String> {
     let x = x_as_str.parse::<f64>()?;
     let y = y_as_str.parse::<f64>()?;
+    let seed = seed_as_str.parse::<u32>()?;
+    Ok(sample(seed, x, y).to_string())
+}
+
+//note that this will be 0 at integer x & y, scaling is left up to the caller
+fn sample(seed: u32, x: f64, y: f64) -> f64 {
     GENERATORS.with(|cell| {
         let mut generators = cell.borrow_mut();
-        let mut entry = generators.entry(seed_as_str.to_string());
-        let generator = match entry {
-            Entry::Occupied(ref mut occ) => occ.get_mut(),
-            Entry::Vacant(vac) => {
-                let seed = seed_as_str.parse::<u32>()?;
-                let perlin = Perlin::new(seed);
-                vac.insert(perlin)
-            }
+        let generator = match generators.entry(seed) {
+            Entry::Occupied(occ) => occ.into_mut(),
+            Entry::Vacant(vac) => vac.insert(Perlin::new(seed)),
         };
         //perlin noise produces a result in [-sqrt(0.5), sqrt(0.5)] which we scale to [0, 1] for simplicity
         let unscaled = generator.get([x, y]);
         let scaled = (unscaled * 2.0_f64.sqrt() + 1.0) / 2.0;
-        let clamped = scaled.clamp(0.0, 1.0);
-        Ok(clamped.to_string())
+        scaled.clamp(0.0, 1.0)
     })
 }
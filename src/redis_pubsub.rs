@@ -1,3 +1,4 @@
+use crate::error::{Error, Reply, Result};
 use redis::{Client, Commands, RedisError};
 use std::cell::RefCell;
 use std::collections::HashMap;
@@ -26,7 +27,7 @@ fn handle_redis_inner(
     client: Client,
     control: &flume::Receiver<PubSubRequest>,
     out: &flume::Sender<PubSubResponse>,
-) -> Result<(), RedisError> {
+) -> std::result::Result<(), RedisError> {
     let mut conn = client.get_connection()?;
     let mut pub_conn = client.get_connection()?;
     let mut pubsub = conn.as_pubsub();
@@ -79,9 +80,11 @@ fn handle_redis(
     }
 }
 
-fn connect(addr: &str) -> Result<(), RedisError> {
-    let client = redis::Client::open(addr)?;
-    let _ = client.get_connection_with_timeout(Duration::from_secs(1))?;
+fn connect(addr: &str) -> Result<()> {
+    let client = redis::Client::open(addr).map_err(redis_error)?;
+    let _ = client
+        .get_connection_with_timeout(Duration::from_secs(1))
+        .map_err(redis_error)?;
     let (c_sender, c_receiver) = flume::bounded(1000);
     let (o_sender, o_receiver) = flume::bounded(1000);
     REQUEST_SENDER.with(|cell| cell.replace(Some(c_sender)));
@@ -100,33 +103,27 @@ fn disconnect() {
     });
 }
 
-// It's lame as hell to use strings as errors, but I don't feel like
-// making a whole new type encompassing possible errors, since we're returning a string
-// to BYOND anyway.
-fn subscribe(channel: &str) -> Option<String> {
-    REQUEST_SENDER.with(|cell| {
-        if let Some(chan) = cell.borrow_mut().as_ref() {
-            chan.try_send(PubSubRequest::Subscribe(channel.to_owned()))
-                .err()
-                .map(|e| e.to_string())
-        } else {
-            Some("Not connected".to_owned())
-        }
-    })
+fn redis_error(error: RedisError) -> Error {
+    Error::Redis(error.to_string())
 }
 
-fn publish(channel: &str, msg: &str) -> Option<String> {
-    REQUEST_SENDER.with(|cell| {
-        if let Some(chan) = cell.borrow_mut().as_ref() {
-            chan.try_send(PubSubRequest::Publish(channel.to_owned(), msg.to_owned()))
-                .err()
-                .map(|e| e.to_string())
-        } else {
-            Some("Not connected".to_owned())
-        }
+fn send(request: PubSubRequest) -> Result<()> {
+    REQUEST_SENDER.with(|cell| match cell.borrow().as_ref() {
+        Some(chan) => chan
+            .try_send(request)
+            .map_err(|e| Error::Redis(e.to_string())),
+        None => Err(Error::Redis("Not connected".to_owned())),
     })
 }
 
+fn subscribe(channel: &str) -> Result<()> {
+    send(PubSubRequest::Subscribe(channel.to_owned()))
+}
+
+fn publish(channel: &str, msg: &str) -> Result<()> {
+    send(PubSubRequest::Publish(channel.to_owned(), msg.to_owned()))
+}
+
 fn get_messages() -> String {
     let mut result: HashMap<String, Vec<String>> = HashMap::new();
 
@@ -154,7 +151,7 @@ fn get_messages() -> String {
 }
 
 byond_fn!(fn redis_connect(addr) {
-    connect(addr).err().map(|e| e.to_string())
+    Reply::error_or_none(connect(addr))
 });
 
 byond_fn!(
@@ -165,7 +162,7 @@ byond_fn!(
 );
 
 byond_fn!(fn redis_subscribe(channel) {
-    subscribe(channel)
+    Reply::error_or_none(subscribe(channel))
 });
 
 byond_fn!(
@@ -175,5 +172,5 @@ byond_fn!(
 );
 
 byond_fn!(fn redis_publish(channel, message) {
-    publish(channel, message)
+    Reply::error_or_none(publish(channel, message))
 });
@@ -1,3 +1,4 @@
+use crate::error::{Error, Reply, Result};
 use redis::{Client, Commands, RedisError};
 use std::cell::RefCell;
 use std::num::NonZeroUsize;
@@ -7,13 +8,19 @@ thread_local! {
     static REDIS_CLIENT: RefCell<Option<Client>> = const { RefCell::new(None) };
 }
 
-fn connect(addr: &str) -> Result<(), RedisError> {
-    let client = redis::Client::open(addr)?;
-    let _ = client.get_connection_with_timeout(Duration::from_secs(1))?;
+fn connect(addr: &str) -> Result<()> {
+    let client = redis::Client::open(addr).map_err(redis_error)?;
+    let _ = client
+        .get_connection_with_timeout(Duration::from_secs(1))
+        .map_err(redis_error)?;
     REDIS_CLIENT.with(|cli| cli.replace(Some(client)));
     Ok(())
 }
 
+fn redis_error(error: RedisError) -> Error {
+    Error::Redis(error.to_string())
+}
+
 fn disconnect() {
     // Drop the client
     REDIS_CLIENT.with(|client| {
@@ -138,7 +145,7 @@ fn lpop(key: &str, count: Option<NonZeroUsize>) -> serde_json::Value {
 }
 
 byond_fn!(fn redis_connect_rq(addr) {
-    connect(addr).err().map(|e| e.to_string())
+    Reply::error_or_none(connect(addr))
 });
 
 byond_fn!(
@@ -0,0 +1,114 @@
+//! Restricts every export which touches the filesystem to a set of root
+//! directories, so a bad path from DM can't reach the server's config.
+//!
+//! No roots are set by default, which leaves paths unrestricted.
+use crate::error::{Error, Reply, Result};
+use std::{
+    env,
+    path::{Component, Path, PathBuf},
+    sync::RwLock,
+};
+
+static ROOTS: RwLock<Vec<PathBuf>> = RwLock::new(Vec::new());
+
+// Takes the allowed roots separated by newlines. Empty lifts the restriction.
+byond_fn!(fn sandbox_set_roots(roots) {
+    Reply::error_or_none(set_roots(roots))
+});
+
+fn set_roots(roots: &str) -> Result<()> {
+    let roots = roots
+        .lines()
+        .map(str::trim)
+        .filter(|root| !root.is_empty())
+        .map(|root| Ok(Path::new(root).canonicalize()?))
+        .collect::<Result<Vec<_>>>()?;
+    *ROOTS.write().map_err(|_| Error::Poisoned)? = roots;
+    Ok(())
+}
+
+/// Resolves `path` against the configured roots, returning it as an absolute
+/// path, or `Error::InvalidFilename` if it falls outside all of them.
+pub fn check(path: impl AsRef<Path>) -> Result<PathBuf> {
+    check_against(&ROOTS.read().map_err(|_| Error::Poisoned)?, path.as_ref())
+}
+
+fn check_against(roots: &[PathBuf], path: &Path) -> Result<PathBuf> {
+    if roots.is_empty() {
+        return Ok(path.to_owned());
+    }
+    let resolved = resolve(path)?;
+    if roots.iter().any(|root| resolved.starts_with(root)) {
+        Ok(resolved)
+    } else {
+        Err(Error::InvalidFilename)
+    }
+}
+
+/// Makes `path` absolute and follows any symlinks in the part of it which
+/// exists, without requiring the rest to. Only the deepest existing ancestor
+/// is canonicalized; the missing part can't contain links, so it's appended
+/// as written.
+fn resolve(path: &Path) -> Result<PathBuf> {
+    let path = if path.is_absolute() {
+        path.to_owned()
+    } else {
+        env::current_dir()?.join(path)
+    };
+    let (mut resolved, missing) = path
+        .ancestors()
+        .find_map(|ancestor| {
+            Some((
+                ancestor.canonicalize().ok()?,
+                path.strip_prefix(ancestor).ok()?,
+            ))
+        })
+        .ok_or(Error::InvalidFilename)?;
+    for component in missing.components() {
+        match component {
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            Component::Normal(name) => resolved.push(name),
+            _ => {}
+        }
+    }
+    Ok(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn paths_must_stay_inside_roots() {
+        let root = TempDir::new("sandbox");
+        std::fs::create_dir_all(root.join("data")).unwrap();
+        let roots = [root.join("data")];
+        let check = |path: PathBuf| check_against(&roots, &path);
+
+        assert!(check(root.join("data/new/file.txt")).is_ok());
+        assert!(check(root.join("data/../data/file.txt")).is_ok());
+        assert!(matches!(
+            check(root.join("data/../config.txt")),
+            Err(Error::InvalidFilename)
+        ));
+        assert!(matches!(
+            check("/etc/passwd".into()),
+            Err(Error::InvalidFilename)
+        ));
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(&*root, root.join("data/link")).unwrap();
+            assert!(matches!(
+                check(root.join("data/link/new/file.txt")),
+                Err(Error::InvalidFilename)
+            ));
+        }
+
+        let unrestricted = check_against(&[], Path::new("/etc/passwd")).unwrap();
+        assert_eq!(unrestricted, Path::new("/etc/passwd"));
+    }
+}
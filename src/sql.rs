@@ -1,9 +1,9 @@
-use crate::jobs;
+use crate::{error::Reply, jobs};
 use dashmap::DashMap;
 use mysql::{
     consts::{ColumnFlags, ColumnType::*},
     prelude::Queryable,
-    OptsBuilder, Params, Pool, PoolConstraints, PoolOpts,
+    OptsBuilder, Params, Pool, PoolConstraints, PoolOpts, PooledConn,
 };
 use once_cell::sync::Lazy;
 use serde::Deserialize;
@@ -32,75 +32,84 @@ struct ConnectOptions {
     max_threads: Option<usize>,
 }
 
+#[derive(Deserialize, Default)]
+struct QueryOptions {
+    timeout: Option<f32>,
+}
+
 byond_fn!(fn sql_connect_pool(options) {
     let options = match serde_json::from_str::<ConnectOptions>(options) {
         Ok(options) => options,
-        Err(e) => return Some(err_to_json(e)),
+        Err(e) => return reply(Err(e.into())),
     };
-    Some(match sql_connect(options) {
-        Ok(o) => o.to_string(),
-        Err(e) => err_to_json(e)
-    })
+    reply(sql_connect(options))
 });
 
 byond_fn!(fn sql_query_blocking(handle, query, params) {
-    Some(match do_query(handle, query, params) {
-        Ok(o) => o.to_string(),
-        Err(e) => err_to_json(e)
-    })
+    reply(do_query(handle, query, params, None))
 });
 
-byond_fn!(fn sql_query_async(handle, query, params) {
+byond_fn!(fn sql_query_async(handle, query, params, options) {
+    let options = if options.is_empty() {
+        QueryOptions::default()
+    } else {
+        match serde_json::from_str::<QueryOptions>(options) {
+            Ok(options) => options,
+            Err(e) => return reply(Err(e.into())),
+        }
+    };
+    let timeout = match secs_to_duration(options.timeout) {
+        Ok(timeout) => timeout,
+        Err(e) => return reply(Err(e.into())),
+    };
     let handle = handle.to_owned();
     let query = query.to_owned();
     let params = params.to_owned();
-    Some(jobs::start(move || {
-        match do_query(&handle, &query, &params) {
-            Ok(o) => o.to_string(),
-            Err(e) => err_to_json(e)
-        }
-    }))
+    let id = jobs::start_with_timeout(move || {
+        reply(do_query(&handle, &query, &params, timeout)).into_string()
+    }, timeout);
+    Reply::value_or_none(Ok(id))
 });
 
 // hopefully won't panic if queries are running
 byond_fn!(fn sql_disconnect_pool(handle) {
     let handle = match handle.parse::<usize>() {
         Ok(o) => o,
-        Err(e) => return Some(err_to_json(e)),
+        Err(e) => return reply(Err(e.into())),
     };
-    Some(
+    reply(Ok(
          match POOL.remove(&handle) {
             Some(_) => {
                 json!({
                     "status": "success"
-                }).to_string()
+                })
             },
             None => json!({
                 "status": "offline"
-            }).to_string()
+            })
         }
-    )
+    ))
 });
 
 byond_fn!(fn sql_connected(handle) {
     let handle = match handle.parse::<usize>() {
         Ok(o) => o,
-        Err(e) => return Some(err_to_json(e)),
+        Err(e) => return reply(Err(e.into())),
     };
-    Some(
+    reply(Ok(
         match POOL.get(&handle) {
             Some(_) => json!({
                 "status": "online"
-            }).to_string(),
+            }),
             None => json!({
                 "status": "offline"
-            }).to_string()
+            })
         }
-    )
+    ))
 });
 
 byond_fn!(fn sql_check_query(id) {
-    Some(jobs::check(id))
+    Reply::raw(jobs::check(id))
 });
 
 // ----------------------------------------------------------------------------
@@ -130,8 +139,8 @@ fn sql_connect(options: ConnectOptions) -> Result<serde_json::Value, Box<dyn Err
         .user(options.user)
         .pass(options.pass)
         .db_name(options.db_name)
-        .read_timeout(options.read_timeout.map(Duration::from_secs_f32))
-        .write_timeout(options.write_timeout.map(Duration::from_secs_f32))
+        .read_timeout(secs_to_duration(options.read_timeout)?)
+        .write_timeout(secs_to_duration(options.write_timeout)?)
         .pool_opts(pool_opts);
 
     let pool = Pool::new(builder)?;
@@ -144,7 +153,12 @@ fn sql_connect(options: ConnectOptions) -> Result<serde_json::Value, Box<dyn Err
     }))
 }
 
-fn do_query(handle: &str, query: &str, params: &str) -> Result<serde_json::Value, Box<dyn Error>> {
+fn do_query(
+    handle: &str,
+    query: &str,
+    params: &str,
+    timeout: Option<Duration>,
+) -> Result<serde_json::Value, Box<dyn Error>> {
     let mut conn = {
         let pool = match POOL.get(&handle.parse()?) {
             Some(s) => s,
@@ -153,7 +167,41 @@ fn do_query(handle: &str, query: &str, params: &str) -> Result<serde_json::Value
         pool.get_conn()?
     };
 
-    let query_result = conn.exec_iter(query, params_from_json(params))?;
+    // The job's timeout only stops waiting for the result, so have the server
+    // give up too rather than let the query hold on to a job worker.
+    if timeout.is_none() {
+        return run_query(&mut conn, query, params);
+    }
+    set_statement_timeout(&mut conn, timeout)?;
+    let result = run_query(&mut conn, query, params);
+    // The connection goes back to the pool, so don't leave the limit on it.
+    set_statement_timeout(&mut conn, None)?;
+    result
+}
+
+/// Limits how long each statement on the connection may run, or lifts the
+/// limit. MariaDB calls this `max_statement_time`, in seconds, and MySQL
+/// `max_execution_time`, in milliseconds, which it only applies to SELECTs.
+fn set_statement_timeout(conn: &mut PooledConn, timeout: Option<Duration>) -> mysql::Result<()> {
+    let timeout = timeout.unwrap_or_default();
+    conn.query_drop(format!(
+        "SET SESSION max_statement_time = {}",
+        timeout.as_secs_f64()
+    ))
+    .or_else(|_| {
+        conn.query_drop(format!(
+            "SET SESSION max_execution_time = {}",
+            timeout.as_millis()
+        ))
+    })
+}
+
+fn run_query(
+    conn: &mut PooledConn,
+    query: &str,
+    params: &str,
+) -> Result<serde_json::Value, Box<dyn Error>> {
+    let query_result = conn.exec_iter(query, params_from_json(params)?)?;
     let affected = query_result.affected_rows();
     let last_insert_id = query_result.last_insert_id();
     let mut columns = Vec::new();
@@ -214,8 +262,6 @@ fn do_query(handle: &str, query: &str, params: &str) -> Result<serde_json::Value
         rows.push(serde_json::Value::Array(json_row));
     }
 
-    drop(conn);
-
     Ok(json! {{
         "status": "ok",
         "affected": affected,
@@ -228,6 +274,26 @@ fn do_query(handle: &str, query: &str, params: &str) -> Result<serde_json::Value
 // ----------------------------------------------------------------------------
 // Helpers
 
+fn reply(result: Result<serde_json::Value, Box<dyn Error>>) -> Reply {
+    let result = result.map(|o| Some(o.to_string())).map_err(|e| {
+        // Keep the kind of our own errors, such as a missing buffer.
+        e.downcast::<crate::error::Error>()
+            .map_or_else(|e| crate::error::Error::Sql(e.to_string()), |e| *e)
+    });
+    Reply::new(result, |r| {
+        Some(match r {
+            Ok(o) => o.unwrap_or_default(),
+            Err(e) => err_to_json(e),
+        })
+    })
+}
+
+/// Rejects timeouts which are negative or not finite, which would otherwise
+/// panic.
+fn secs_to_duration(secs: Option<f32>) -> crate::error::Result<Option<Duration>> {
+    Ok(secs.map(Duration::try_from_secs_f32).transpose()?)
+}
+
 fn err_to_json<E: std::fmt::Display>(e: E) -> String {
     json!({
         "status": "err",
@@ -236,8 +302,8 @@ fn err_to_json<E: std::fmt::Display>(e: E) -> String {
     .to_string()
 }
 
-fn json_to_mysql(val: serde_json::Value) -> mysql::Value {
-    match val {
+fn json_to_mysql(val: serde_json::Value) -> crate::error::Result<mysql::Value> {
+    Ok(match val {
         serde_json::Value::Bool(b) => mysql::Value::UInt(b as u64),
         serde_json::Value::Number(i) => {
             if let Some(v) = i.as_u64() {
@@ -251,6 +317,12 @@ fn json_to_mysql(val: serde_json::Value) -> mysql::Value {
             }
         }
         serde_json::Value::String(s) => mysql::Value::Bytes(s.into()),
+        // `{"buffer": handle}` binds the contents of a byte buffer as a blob.
+        #[cfg(feature = "buffer")]
+        serde_json::Value::Object(o) => match o.get("buffer").and_then(|h| h.as_str()) {
+            Some(handle) => mysql::Value::Bytes(crate::buffer::get(handle)?.to_vec()),
+            None => mysql::Value::NULL,
+        },
         serde_json::Value::Array(a) => mysql::Value::Bytes(
             a.into_iter()
                 .map(|x| {
@@ -263,37 +335,44 @@ fn json_to_mysql(val: serde_json::Value) -> mysql::Value {
                 .collect(),
         ),
         _ => mysql::Value::NULL,
-    }
+    })
 }
 
-fn array_to_params(params: Vec<serde_json::Value>) -> Params {
+fn array_to_params(params: Vec<serde_json::Value>) -> crate::error::Result<Params> {
     if params.is_empty() {
-        Params::Empty
+        Ok(Params::Empty)
     } else {
-        Params::Positional(params.into_iter().map(json_to_mysql).collect())
+        Ok(Params::Positional(
+            params
+                .into_iter()
+                .map(json_to_mysql)
+                .collect::<crate::error::Result<_>>()?,
+        ))
     }
 }
 
-fn object_to_params(params: Map<std::string::String, serde_json::Value>) -> Params {
+fn object_to_params(
+    params: Map<std::string::String, serde_json::Value>,
+) -> crate::error::Result<Params> {
     if params.is_empty() {
-        Params::Empty
+        Ok(Params::Empty)
     } else {
-        Params::Named(
+        Ok(Params::Named(
             params
                 .into_iter()
                 .map(|(key, val)| {
                     let key_bytes: Vec<u8> = key.into_bytes();
-                    (key_bytes, json_to_mysql(val))
+                    Ok((key_bytes, json_to_mysql(val)?))
                 })
-                .collect::<HashMap<_, _>>(),
-        )
+                .collect::<crate::error::Result<HashMap<_, _>>>()?,
+        ))
     }
 }
 
-fn params_from_json(params: &str) -> Params {
+fn params_from_json(params: &str) -> crate::error::Result<Params> {
     match serde_json::from_str(params) {
         Ok(serde_json::Value::Object(o)) => object_to_params(o),
         Ok(serde_json::Value::Array(a)) => array_to_params(a),
-        _ => Params::Empty,
+        _ => Ok(Params::Empty),
     }
 }
@@ -0,0 +1,36 @@
+//! Fixtures shared by the unit tests.
+use std::{
+    fs,
+    ops::Deref,
+    path::{Path, PathBuf},
+};
+
+/// A fresh directory under the system temp dir, removed again when dropped,
+/// even if the test panics.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Creates `rustg-<name>-<pid>`, canonicalized so it can be compared
+    /// against resolved paths.
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("rustg-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        Self(path.canonicalize().unwrap())
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        // Some tests delete it themselves.
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
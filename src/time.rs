@@ -1,31 +1,29 @@
+use crate::error::{Error, Reply, Result};
+use chrono::{
+    format::{Item, StrftimeItems},
+    DateTime, Datelike, FixedOffset, Local, Months, NaiveDate, NaiveDateTime, TimeZone, Utc,
+};
 use std::{
     cell::RefCell,
-    collections::hash_map::{Entry, HashMap},
-    time::Instant,
+    collections::{BTreeMap, HashMap},
+    str::FromStr,
+    time::{Duration, Instant},
 };
 
 thread_local!( static INSTANTS: RefCell<HashMap<String, Instant>> = RefCell::new(HashMap::new()) );
+// Timers for the byondapi exports, which are keyed by number rather than by string.
+thread_local!( static NATIVE_INSTANTS: RefCell<HashMap<u32, Instant>> = RefCell::new(HashMap::new()) );
+// Profiling spans: the start of each open span, as a stack so a span can nest
+// inside itself, and the durations recorded for each name.
+thread_local!( static OPEN_SPANS: RefCell<HashMap<String, Vec<Instant>>> = RefCell::new(HashMap::new()) );
+thread_local!( static SPAN_STATS: RefCell<BTreeMap<String, Histogram>> = const { RefCell::new(BTreeMap::new()) } );
 
 byond_fn!(fn time_microseconds(instant_id) {
-    INSTANTS.with(|instants| {
-        let mut map = instants.borrow_mut();
-        let instant = match map.entry(instant_id.into()) {
-            Entry::Occupied(elem) => elem.into_mut(),
-            Entry::Vacant(elem) => elem.insert(Instant::now()),
-        };
-        Some(instant.elapsed().as_micros().to_string())
-    })
+    Some(instant(instant_id).elapsed().as_micros().to_string())
 });
 
 byond_fn!(fn time_milliseconds(instant_id) {
-    INSTANTS.with(|instants| {
-        let mut map = instants.borrow_mut();
-        let instant = match map.entry(instant_id.into()) {
-            Entry::Occupied(elem) => elem.into_mut(),
-            Entry::Vacant(elem) => elem.insert(Instant::now()),
-        };
-        Some(instant.elapsed().as_millis().to_string())
-    })
+    Some(instant(instant_id).elapsed().as_millis().to_string())
 });
 
 byond_fn!(fn time_reset(instant_id) {
@@ -36,6 +34,208 @@ byond_fn!(fn time_reset(instant_id) {
     })
 });
 
+byond_fn_native!(fn time_microseconds_native(instant_id) {
+    Some(native_instant(instant_id?).elapsed().as_micros() as f32)
+});
+
+byond_fn_native!(fn time_milliseconds_native(instant_id) {
+    Some(native_instant(instant_id?).elapsed().as_millis() as f32)
+});
+
+byond_fn_native!(fn time_reset_native(instant_id) {
+    NATIVE_INSTANTS.with(|instants| {
+        instants.borrow_mut().insert(instant_id?.to_bits(), Instant::now());
+        None
+    })
+});
+
+/// A timezone given by name: `"utc"` (or empty), `"local"`, or a fixed offset
+/// such as `"+10:00"`.
+#[derive(Clone, Copy)]
+pub enum Timezone {
+    Utc,
+    Local,
+    Fixed(FixedOffset),
+}
+
+impl FromStr for Timezone {
+    type Err = Error;
+
+    fn from_str(timezone: &str) -> Result<Self> {
+        match timezone.trim().to_ascii_lowercase().as_str() {
+            "" | "utc" => Ok(Self::Utc),
+            "local" => Ok(Self::Local),
+            offset => offset
+                .parse()
+                .map(Self::Fixed)
+                .map_err(|_| Error::InvalidTimezone),
+        }
+    }
+}
+
+impl Timezone {
+    /// Formats `time` in this timezone. `format` must have passed `check_format`.
+    pub fn format(&self, time: DateTime<Utc>, format: &str) -> String {
+        match self {
+            Self::Utc => time.format(format).to_string(),
+            Self::Local => time.with_timezone(&Local).format(format).to_string(),
+            Self::Fixed(offset) => time.with_timezone(offset).format(format).to_string(),
+        }
+    }
+
+    pub fn localize(&self, time: DateTime<Utc>) -> NaiveDateTime {
+        match self {
+            Self::Utc => time.naive_utc(),
+            Self::Local => time.with_timezone(&Local).naive_local(),
+            Self::Fixed(offset) => time.with_timezone(offset).naive_local(),
+        }
+    }
+
+    /// Where a clock change makes a local time ambiguous this takes the
+    /// earlier one, and where it skips a local time this gives `None`.
+    pub fn resolve(&self, local: NaiveDateTime) -> Option<DateTime<Utc>> {
+        match self {
+            Self::Utc => Some(Utc.from_utc_datetime(&local)),
+            Self::Local => Local
+                .from_local_datetime(&local)
+                .earliest()
+                .map(|time| time.with_timezone(&Utc)),
+            Self::Fixed(offset) => offset
+                .from_local_datetime(&local)
+                .earliest()
+                .map(|time| time.with_timezone(&Utc)),
+        }
+    }
+}
+
+/// Checks a strftime format up front, since formatting with a bad one panics.
+pub fn check_format(format: &str) -> Result<()> {
+    if StrftimeItems::new(format).any(|item| matches!(item, Item::Error)) {
+        return Err(Error::InvalidTimeFormat);
+    }
+    Ok(())
+}
+
+enum Unit {
+    Fixed(f64),
+    Months(i32),
+}
+
+impl FromStr for Unit {
+    type Err = Error;
+
+    fn from_str(unit: &str) -> Result<Self> {
+        Ok(
+            match unit.trim().to_ascii_lowercase().trim_end_matches('s') {
+                "second" => Unit::Fixed(1.0),
+                "minute" => Unit::Fixed(60.0),
+                "hour" => Unit::Fixed(3600.0),
+                "day" => Unit::Fixed(86400.0),
+                "week" => Unit::Fixed(604_800.0),
+                "month" => Unit::Months(1),
+                "year" => Unit::Months(12),
+                _ => return Err(Error::InvalidTimeUnit),
+            },
+        )
+    }
+}
+
+pub fn from_timestamp(timestamp: &str) -> Result<DateTime<Utc>> {
+    let micros = timestamp.trim().parse::<f64>()? * 1e6;
+    // "nan" and "inf" parse, but would otherwise land on the epoch or the limits.
+    if !micros.is_finite() {
+        return Err(Error::TimeOutOfRange);
+    }
+    DateTime::from_timestamp_micros(micros.round() as i64).ok_or(Error::TimeOutOfRange)
+}
+
+pub fn to_timestamp(time: DateTime<Utc>) -> String {
+    (time.timestamp_micros() as f64 / 1e6).to_string()
+}
+
+fn parse_standard(text: &str, timezone: &Timezone) -> Result<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(text) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    if let Ok(time) = DateTime::parse_from_rfc2822(text) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    // ISO 8601 allows leaving the offset, the seconds or the time out entirely.
+    let mut result = parse_custom(text, "%Y-%m-%dT%H:%M:%S%.f", timezone);
+    for format in [
+        "%Y-%m-%d %H:%M:%S%.f",
+        "%Y-%m-%dT%H:%M",
+        "%Y-%m-%d %H:%M",
+        "%Y-%m-%d",
+    ] {
+        if result.is_ok() {
+            break;
+        }
+        result = parse_custom(text, format, timezone);
+    }
+    result
+}
+
+fn parse_custom(text: &str, format: &str, timezone: &Timezone) -> Result<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_str(text, format) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    let local = match NaiveDateTime::parse_from_str(text, format) {
+        Ok(local) => local,
+        Err(error) => match NaiveDate::parse_from_str(text, format) {
+            Ok(date) => date.and_time(Default::default()),
+            Err(_) => return Err(error.into()),
+        },
+    };
+    timezone.resolve(local).ok_or(Error::TimeOutOfRange)
+}
+
+/// Adds calendar months, keeping the day of the month where it exists and
+/// otherwise using the last day, e.g. Jan 31st plus a month is Feb 28th/29th.
+fn add_months(local: NaiveDateTime, months: i32) -> Option<NaiveDateTime> {
+    if months < 0 {
+        local.checked_sub_months(Months::new(months.unsigned_abs()))
+    } else {
+        local.checked_add_months(Months::new(months as u32))
+    }
+}
+
+fn months_between(from: NaiveDateTime, to: NaiveDateTime) -> i32 {
+    let mut months = (to.year() - from.year()) * 12 + to.month() as i32 - from.month() as i32;
+    if months > 0 && add_months(from, months).map_or(true, |end| end > to) {
+        months -= 1;
+    } else if months < 0 && add_months(from, months).map_or(true, |end| end < to) {
+        months += 1;
+    }
+    months
+}
+
+/// Gets the instant for `instant_id`, starting it if it doesn't exist yet.
+pub fn instant(instant_id: &str) -> Instant {
+    INSTANTS.with(|instants| {
+        *instants
+            .borrow_mut()
+            .entry(instant_id.to_owned())
+            .or_insert_with(Instant::now)
+    })
+}
+
+/// How long ago the timer `instant_id` started, without starting it.
+pub fn elapsed(instant_id: &str) -> Result<Duration> {
+    INSTANTS
+        .with(|instants| instants.borrow().get(instant_id).map(Instant::elapsed))
+        .ok_or(Error::UnknownTimer)
+}
+
+fn native_instant(instant_id: f32) -> Instant {
+    NATIVE_INSTANTS.with(|instants| {
+        *instants
+            .borrow_mut()
+            .entry(instant_id.to_bits())
+            .or_insert_with(Instant::now)
+    })
+}
+
 byond_fn!(
     fn unix_timestamp() {
         Some(format!(
@@ -47,3 +247,258 @@ byond_fn!(
         ))
     }
 );
+
+// Timestamps below are unix timestamps passed as text, since DM's numbers are
+// too imprecise to hold them. Timezones are as `Timezone` parses them, with
+// empty meaning UTC.
+
+byond_fn!(fn time_format(timestamp, format, timezone) {
+    Reply::value_or_none((|| {
+        let time = from_timestamp(timestamp)?;
+        let format = if format.is_empty() { "%FT%T%:z" } else { format };
+        check_format(format)?;
+        Ok(timezone.parse::<Timezone>()?.format(time, format))
+    })())
+});
+
+// Parses RFC 3339/ISO 8601 or RFC 2822 if the format is empty, and a strftime
+// format otherwise. Times without an offset are taken to be in the timezone.
+byond_fn!(fn time_parse(text, format, timezone) {
+    Reply::value_or_none((|| {
+        let timezone = timezone.parse::<Timezone>()?;
+        let time = if format.is_empty() {
+            parse_standard(text.trim(), &timezone)?
+        } else {
+            check_format(format)?;
+            parse_custom(text.trim(), format, &timezone)?
+        };
+        Ok(to_timestamp(time))
+    })())
+});
+
+byond_fn!(fn time_add(timestamp, amount, unit, timezone) {
+    Reply::value_or_none((|| {
+        let time = from_timestamp(timestamp)?;
+        let time = match unit.parse::<Unit>()? {
+            Unit::Fixed(seconds) => {
+                let micros = amount.parse::<f64>()? * seconds * 1e6;
+                time.checked_add_signed(chrono::Duration::microseconds(micros.round() as i64))
+            }
+            Unit::Months(months) => {
+                let timezone = timezone.parse::<Timezone>()?;
+                let months = amount.parse::<i32>()?.checked_mul(months).ok_or(Error::TimeOutOfRange)?;
+                add_months(timezone.localize(time), months).and_then(|local| timezone.resolve(local))
+            }
+        };
+        Ok(to_timestamp(time.ok_or(Error::TimeOutOfRange)?))
+    })())
+});
+
+// How many units `to` is after `from`. Calendar units only count whole ones.
+byond_fn!(fn time_diff(from, to, unit, timezone) {
+    Reply::value_or_none((|| {
+        let (from, to) = (from_timestamp(from)?, from_timestamp(to)?);
+        Ok(match unit.parse::<Unit>()? {
+            Unit::Fixed(seconds) => {
+                let micros = (to - from).num_microseconds().ok_or(Error::TimeOutOfRange)?;
+                (micros as f64 / 1e6 / seconds).to_string()
+            }
+            Unit::Months(months) => {
+                let timezone = timezone.parse::<Timezone>()?;
+                (months_between(timezone.localize(from), timezone.localize(to)) / months).to_string()
+            }
+        })
+    })())
+});
+
+byond_fn!(fn time_span_begin(name) {
+    OPEN_SPANS.with(|spans| {
+        spans
+            .borrow_mut()
+            .entry(name.to_owned())
+            .or_default()
+            .push(Instant::now());
+    });
+    Some("")
+});
+
+// Returns the span's duration in milliseconds, or nothing if it wasn't begun.
+byond_fn!(fn time_span_end(name) {
+    let start = OPEN_SPANS.with(|spans| spans.borrow_mut().get_mut(name)?.pop())?;
+    let elapsed = start.elapsed();
+    SPAN_STATS.with(|stats| {
+        stats
+            .borrow_mut()
+            .entry(name.to_owned())
+            .or_default()
+            .record(elapsed);
+    });
+    Some((elapsed.as_secs_f64() * 1000.0).to_string())
+});
+
+byond_fn!(
+    fn time_stats() {
+        SPAN_STATS.with(|stats| {
+            let stats: serde_json::Map<_, _> = stats
+                .borrow()
+                .iter()
+                .map(|(name, histogram)| (name.clone(), histogram.summary()))
+                .collect();
+            Some(serde_json::Value::Object(stats).to_string())
+        })
+    }
+);
+
+// Forgets the stats and any open spans for a name, or for everything if it's empty.
+byond_fn!(fn time_stats_reset(name) {
+    if name.is_empty() {
+        OPEN_SPANS.with(|spans| spans.borrow_mut().clear());
+        SPAN_STATS.with(|stats| stats.borrow_mut().clear());
+    } else {
+        OPEN_SPANS.with(|spans| spans.borrow_mut().remove(name));
+        SPAN_STATS.with(|stats| stats.borrow_mut().remove(name));
+    }
+    Some("")
+});
+
+/// Span durations in microseconds, bucketed so that memory stays bounded no
+/// matter how many are recorded. Below 16µs every value has its own bucket;
+/// above that each power of two is split into 16, so percentiles are within
+/// about 6% of the real value. Count, total and max are exact.
+#[derive(Default)]
+struct Histogram {
+    buckets: Vec<u64>,
+    count: u64,
+    total: u128,
+    max: u64,
+}
+
+impl Histogram {
+    const SUB_BUCKETS: u64 = 16;
+    const SUB_BITS: u32 = Self::SUB_BUCKETS.trailing_zeros();
+
+    fn record(&mut self, duration: Duration) {
+        let micros = u64::try_from(duration.as_micros()).unwrap_or(u64::MAX);
+        let bucket = Self::bucket(micros);
+        if self.buckets.len() <= bucket {
+            self.buckets.resize(bucket + 1, 0);
+        }
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.total += u128::from(micros);
+        self.max = self.max.max(micros);
+    }
+
+    fn bucket(micros: u64) -> usize {
+        if micros < Self::SUB_BUCKETS {
+            return micros as usize;
+        }
+        let exponent = 63 - micros.leading_zeros();
+        let shift = exponent - Self::SUB_BITS;
+        let mantissa = (micros >> shift) & (Self::SUB_BUCKETS - 1);
+        ((u64::from(shift) + 1) * Self::SUB_BUCKETS + mantissa) as usize
+    }
+
+    /// The largest value which falls into `bucket`.
+    fn bucket_max(bucket: usize) -> u64 {
+        let bucket = bucket as u64;
+        if bucket < Self::SUB_BUCKETS {
+            return bucket;
+        }
+        let shift = bucket / Self::SUB_BUCKETS - 1;
+        let mantissa = bucket % Self::SUB_BUCKETS;
+        ((Self::SUB_BUCKETS + mantissa) << shift) + ((1 << shift) - 1)
+    }
+
+    /// The value at or below which `fraction` of the recorded values fall.
+    fn percentile(&self, fraction: f64) -> u64 {
+        let rank = ((self.count as f64 * fraction).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, &count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Self::bucket_max(bucket).min(self.max);
+            }
+        }
+        self.max
+    }
+
+    /// `{"count", "mean", "p50", "p95", "p99", "max"}`, in milliseconds.
+    fn summary(&self) -> serde_json::Value {
+        let millis = |micros: u64| micros as f64 / 1000.0;
+        let mean = if self.count == 0 {
+            0.0
+        } else {
+            self.total as f64 / self.count as f64 / 1000.0
+        };
+        serde_json::json!({
+            "count": self.count,
+            "mean": mean,
+            "p50": millis(self.percentile(0.50)),
+            "p95": millis(self.percentile(0.95)),
+            "p99": millis(self.percentile(0.99)),
+            "max": millis(self.max),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets_round_trip() {
+        for micros in [0, 15, 16, 31, 32, 33, 1000, 123_456_789] {
+            let max = Histogram::bucket_max(Histogram::bucket(micros));
+            assert!(
+                max >= micros && max - micros <= micros / 16,
+                "{micros} -> {max}"
+            );
+        }
+    }
+
+    #[test]
+    fn histogram_percentiles() {
+        let mut histogram = Histogram::default();
+        for millis in 1..=100 {
+            histogram.record(Duration::from_millis(millis));
+        }
+        let summary = histogram.summary();
+        assert_eq!(summary["count"], 100);
+        assert_eq!(summary["mean"], 50.5);
+        assert_eq!(summary["max"], 100.0);
+        let p50 = summary["p50"].as_f64().unwrap();
+        let p99 = summary["p99"].as_f64().unwrap();
+        assert!((50.0..=53.0).contains(&p50), "{p50}");
+        assert!((99.0..=100.0).contains(&p99), "{p99}");
+    }
+
+    #[test]
+    fn calendar_arithmetic() {
+        let timezone: Timezone = "+02:00".parse().unwrap();
+        let time = parse_standard("2024-01-31 12:00", &timezone).unwrap();
+        assert_eq!(to_timestamp(time), "1706695200");
+        assert_eq!(
+            timezone.format(time, "%F %R %:z"),
+            "2024-01-31 12:00 +02:00"
+        );
+
+        let next = timezone
+            .resolve(add_months(timezone.localize(time), 1).unwrap())
+            .unwrap();
+        assert_eq!(timezone.format(next, "%F %R"), "2024-02-29 12:00");
+        let (from, to) = (timezone.localize(time), timezone.localize(next));
+        assert_eq!(months_between(from, to), 1);
+        assert_eq!(months_between(from + chrono::Duration::days(40), from), -1);
+        assert_eq!(months_between(from, to - chrono::Duration::seconds(1)), 0);
+
+        let rfc2822 = parse_standard("Wed, 31 Jan 2024 10:00:00 +0000", &Timezone::Utc).unwrap();
+        assert_eq!(rfc2822, time);
+        let custom = parse_custom("31/01/24 10h00", "%d/%m/%y %Hh%M", &Timezone::Utc).unwrap();
+        assert_eq!(custom, time);
+        assert!(check_format("%Q").is_err());
+        assert!(matches!(from_timestamp("nan"), Err(Error::TimeOutOfRange)));
+        assert!(matches!(from_timestamp("-inf"), Err(Error::TimeOutOfRange)));
+        assert!("mars".parse::<Timezone>().is_err());
+    }
+}
@@ -1,38 +1,22 @@
-use crate::error::Result;
+use crate::{
+    error::{Reply, Result},
+    sandbox,
+};
 
 byond_fn!(fn toml_file_to_json(path) {
-    serde_json::to_string(
-        &match toml_file_to_json_impl(path) {
-            Ok(value) => serde_json::json!({
-                "success": true, "content": value
-            }),
-            Err(error) => serde_json::json!({
-                "success": false, "content": error.to_string()
-            }),
-        }
-    ).ok()
+    Reply::new(toml_file_to_json_impl(path).map(Some), legacy_reply)
 });
 
 fn toml_file_to_json_impl(path: &str) -> Result<String> {
     Ok(serde_json::to_string(&toml_dep::from_str::<
         toml_dep::Value,
     >(&std::fs::read_to_string(
-        path,
+        sandbox::check(path)?,
     )?)?)?)
 }
 
 byond_fn!(fn toml_encode(value) {
-    serde_json::to_string(
-        &match toml_encode_impl(value) {
-            Ok(value) => serde_json::json!({
-                "success": true, "content": value
-            }),
-
-            Err(error) => serde_json::json!({
-                "success": false, "content": error.to_string()
-            }),
-        }
-    ).ok()
+    Reply::new(toml_encode_impl(value).map(Some), legacy_reply)
 });
 
 fn toml_encode_impl(value: &str) -> Result<String> {
@@ -40,3 +24,15 @@ fn toml_encode_impl(value: &str) -> Result<String> {
         toml_dep::Value,
     >(value)?)?)
 }
+
+fn legacy_reply(result: Result<Option<String>>) -> Option<String> {
+    serde_json::to_string(&match result {
+        Ok(value) => serde_json::json!({
+            "success": true, "content": value
+        }),
+        Err(error) => serde_json::json!({
+            "success": false, "content": error.to_string()
+        }),
+    })
+    .ok()
+}
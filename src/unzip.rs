@@ -1,34 +1,46 @@
-use crate::{error::Result, http::HTTP_CLIENT, jobs};
+use crate::{
+    error::{Error, Reply, Result},
+    http::HTTP_CLIENT,
+    jobs, sandbox,
+};
 use reqwest::blocking::RequestBuilder;
 use std::fs;
 use std::io::Write;
-use std::path::Path;
+use std::path::PathBuf;
 use zip::ZipArchive;
 
 struct UnzipPrep {
     req: RequestBuilder,
-    unzip_directory: String,
+    unzip_directory: PathBuf,
 }
 
-fn construct_unzip(url: &str, unzip_directory: &str) -> UnzipPrep {
+fn construct_unzip(url: &str, unzip_directory: &str) -> Result<UnzipPrep> {
     let req = HTTP_CLIENT.get(url);
-    let dir_copy = unzip_directory.to_string();
+    let unzip_directory = sandbox::check(unzip_directory)?;
 
-    UnzipPrep {
+    Ok(UnzipPrep {
         req,
-        unzip_directory: dir_copy,
-    }
+        unzip_directory,
+    })
+}
+
+fn legacy_reply(result: Result<Option<String>>) -> Option<String> {
+    Some(result.map_or_else(|e| e.to_string(), Option::unwrap_or_default))
 }
 
 byond_fn!(fn unzip_download_async(url, unzip_directory) {
-    let unzip = construct_unzip(url, unzip_directory);
-    Some(jobs::start(move ||
-        do_unzip_download(unzip).unwrap_or_else(|e| e.to_string())
-    ))
+    let unzip = match construct_unzip(url, unzip_directory) {
+        Ok(unzip) => unzip,
+        Err(e) => return Reply::new(Err(e), legacy_reply),
+    };
+    let id = jobs::start(move || {
+        Reply::new(do_unzip_download(unzip).map(Some), legacy_reply).into_string()
+    });
+    Reply::new(Ok(Some(id)), legacy_reply)
 });
 
 fn do_unzip_download(prep: UnzipPrep) -> Result<String> {
-    let unzip_path = Path::new(&prep.unzip_directory);
+    let unzip_path = &prep.unzip_directory;
     let response = prep.req.send()?;
 
     let content = response.bytes()?;
@@ -39,7 +51,9 @@ fn do_unzip_download(prep: UnzipPrep) -> Result<String> {
     for i in 0..archive.len() {
         let mut entry = archive.by_index(i)?;
 
-        let file_path = unzip_path.join(entry.name());
+        // Entries like `../../config.txt` would otherwise escape the directory.
+        let name = entry.enclosed_name().ok_or(Error::InvalidFilename)?;
+        let file_path = sandbox::check(unzip_path.join(name))?;
 
         if let Some(parent) = file_path.parent() {
             fs::create_dir_all(parent)?
@@ -60,5 +74,5 @@ fn do_unzip_download(prep: UnzipPrep) -> Result<String> {
 }
 
 byond_fn!(fn unzip_check(id) {
-    Some(jobs::check(id))
+    Reply::raw(jobs::check(id))
 });
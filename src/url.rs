@@ -1,4 +1,4 @@
-use crate::error::Result;
+use crate::error::{Reply, Result};
 use std::borrow::Cow;
 use url_dep::form_urlencoded::byte_serialize;
 
@@ -7,7 +7,7 @@ byond_fn!(fn url_encode(data) {
 });
 
 byond_fn!(fn url_decode(data) {
-    decode(data).ok()
+    Reply::value_or_none(decode(data))
 });
 
 fn encode(string: &str) -> String {